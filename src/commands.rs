@@ -75,19 +75,15 @@ impl Commands {
             return;
         }
 
-        // Read the response
-        let mut buffer = [0; 1024];
-        match stream.read(&mut buffer) {
+        // Read the response, the server closes the connection after writing it
+        let mut buffer = Vec::new();
+        match stream.read_to_end(&mut buffer) {
             Ok(n) => {
                 if n == 0 {
                     tracing::error!("Received empty response");
                     return;
                 }
-                if n >= buffer.len() {
-                    tracing::error!("Response too large");
-                    return;
-                }
-                let response_str = std::str::from_utf8(&buffer[..n]).unwrap_or_default().trim();
+                let response_str = std::str::from_utf8(&buffer).unwrap_or_default().trim();
                 let res_command: Commands = match serde_json::from_str(response_str) {
                     Ok(cmd) => cmd,
                     Err(e) => {
@@ -147,6 +143,13 @@ fn process_command(
             "test" => {
                 handle_test_command(stream, &mut res_command)?;
             }
+            "status" => {
+                handle_status_command(stream, &mut res_command)?;
+            }
+            message if message.starts_with("purge ") => {
+                let key = message.trim_start_matches("purge ").trim();
+                handle_purge_command(stream, &mut res_command, key)?;
+            }
             _ => {
                 tracing::info!("Received unknown command: {:?}", command.message);
             }
@@ -211,3 +214,43 @@ fn handle_test_command(
         Ok(())
     })
 }
+
+fn handle_status_command(
+    stream: &mut UnixStream,
+    res_command: &mut Commands,
+) -> Result<(), Box<dyn std::error::Error>> {
+    res_command.message = crate::config::store::status();
+    // Send response
+    let res_command_str = serde_json::to_string(&res_command)?;
+    stream.write_all(res_command_str.as_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn handle_purge_command(
+    stream: &mut UnixStream,
+    res_command: &mut Commands,
+    key: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        match crate::config::cache::purge(key).await {
+            Ok(true) => {
+                res_command.message = format!("Purged {}", key);
+            }
+            Ok(false) => {
+                res_command.message = format!("{} is not cached", key);
+            }
+            Err(e) => {
+                tracing::error!("Error purging cache: {:?}", e);
+                res_command.message_type = "error".to_string();
+                res_command.message = format!("Error: {:?}", e);
+            }
+        }
+        // Send response
+        let res_command_str = serde_json::to_string(&res_command)?;
+        stream.write_all(res_command_str.as_bytes())?;
+        stream.flush()?;
+        Ok(())
+    })
+}
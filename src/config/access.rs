@@ -0,0 +1,253 @@
+use super::proxy::Access;
+use crate::errors::Errors;
+use std::{net::IpAddr, path::PathBuf, str::FromStr, sync::RwLock, time::SystemTime};
+
+// IPv4 or IPv6 network, a plain address is a /32 or /128
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = Errors;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Errors::ConfigError(format!("Invalid IP or CIDR: {}", s));
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        // clients on a dual stack listener show up as IPv4-mapped IPv6 addresses
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// one CIDR per line, `#` starts a comment
+pub fn parse_list(data: &str) -> Result<Vec<Cidr>, Errors> {
+    data.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(Cidr::from_str)
+        .collect()
+}
+
+#[derive(Debug)]
+struct ListFile {
+    path: PathBuf,
+    allow: bool,
+    modified: Option<SystemTime>,
+    entries: Vec<Cidr>,
+}
+
+// Access control by client IP.
+// `allow_first`: an allowed IP is accepted even when it is also denied,
+// otherwise a denied IP is rejected even when it is also allowed.
+// IPs in neither list are accepted only when there is no allow list.
+#[derive(Debug)]
+pub struct AccessList {
+    name: String,
+    allow_first: bool,
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    // hot-reloaded by the background service
+    files: RwLock<Vec<ListFile>>,
+    pub status: u16,
+    pub message: String,
+}
+
+impl AccessList {
+    pub fn new(name: &str, config: &Access) -> Result<Self, Errors> {
+        let allow_first = match config.order.as_deref() {
+            Some("allow_first") => true,
+            None | Some("deny_first") => false,
+            Some(order) => {
+                return Err(Errors::ConfigError(format!(
+                    "Invalid access order for {}: {}, must be allow_first or deny_first",
+                    name, order
+                )));
+            }
+        };
+        let parse = |list: &Option<Vec<String>>| -> Result<Vec<Cidr>, Errors> {
+            list.iter().flatten().map(|c| c.parse()).collect()
+        };
+        let mut files = Vec::new();
+        for (path, allow) in [(&config.allow_file, true), (&config.deny_file, false)] {
+            if let Some(path) = path {
+                let mut file = ListFile {
+                    path: PathBuf::from(path),
+                    allow,
+                    modified: None,
+                    entries: Vec::new(),
+                };
+                reload_file(&mut file)?;
+                files.push(file);
+            }
+        }
+        Ok(Self {
+            name: name.to_string(),
+            allow_first,
+            allow: parse(&config.allow)?,
+            deny: parse(&config.deny)?,
+            files: RwLock::new(files),
+            status: config.status.unwrap_or(403),
+            message: config
+                .message
+                .clone()
+                .unwrap_or_else(|| "Access denied".to_string()),
+        })
+    }
+
+    pub fn has_files(&self) -> bool {
+        match self.files.read() {
+            Ok(files) => !files.is_empty(),
+            Err(e) => !e.into_inner().is_empty(),
+        }
+    }
+
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        let files = match self.files.read() {
+            Ok(val) => val,
+            Err(e) => e.into_inner(),
+        };
+        let mut has_allow = !self.allow.is_empty();
+        let mut allowed = self.allow.iter().any(|c| c.contains(ip));
+        let mut denied = self.deny.iter().any(|c| c.contains(ip));
+        for file in files.iter() {
+            if file.allow {
+                has_allow = true;
+                allowed = allowed || file.entries.iter().any(|c| c.contains(ip));
+            } else {
+                denied = denied || file.entries.iter().any(|c| c.contains(ip));
+            }
+        }
+        match (allowed, denied) {
+            (true, true) => self.allow_first,
+            (true, false) => true,
+            (false, true) => false,
+            (false, false) => !has_allow,
+        }
+    }
+
+    // re-read the list files that changed, a broken file keeps the previous entries
+    pub fn reload(&self) {
+        let mut files = match self.files.write() {
+            Ok(val) => val,
+            Err(e) => e.into_inner(),
+        };
+        for file in files.iter_mut() {
+            if let Err(e) = reload_file(file) {
+                tracing::error!("Unable to reload access list of {}: {}", self.name, e);
+            }
+        }
+    }
+}
+
+fn reload_file(file: &mut ListFile) -> Result<(), Errors> {
+    let modified = std::fs::metadata(&file.path)
+        .and_then(|m| m.modified())
+        .map_err(|e| {
+            Errors::ConfigError(format!("Unable to read access list {:?}: {}", file.path, e))
+        })?;
+    if file.modified == Some(modified) {
+        return Ok(());
+    }
+    let data = std::fs::read_to_string(&file.path).map_err(|e| {
+        Errors::ConfigError(format!("Unable to read access list {:?}: {}", file.path, e))
+    })?;
+    file.entries = parse_list(&data)?;
+    if file.modified.is_some() {
+        tracing::info!("Reloaded access list {:?}", file.path);
+    }
+    file.modified = Some(modified);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(&ip("10.1.2.3")));
+        assert!(net.contains(&ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(&ip("10.2.0.1")));
+        let net: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains(&ip("2001:db8:1::1")));
+        assert!(!net.contains(&ip("2001:db9::1")));
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(&ip("192.168.1.1")));
+        assert!("10.0.0.1/33".parse::<Cidr>().is_err());
+        assert_eq!(
+            parse_list("# office\n10.0.0.1\n\n192.168.0.0/24 # vpn\n").unwrap(),
+            vec![
+                "10.0.0.1/32".parse::<Cidr>().unwrap(),
+                "192.168.0.0/24".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_access_order() {
+        let config = Access {
+            allow: Some(vec!["10.0.0.0/8".to_string()]),
+            deny: Some(vec!["10.0.0.1".to_string()]),
+            ..Default::default()
+        };
+        let deny_first = AccessList::new("test", &config).unwrap();
+        assert!(deny_first.is_allowed(&ip("10.0.0.2")));
+        assert!(!deny_first.is_allowed(&ip("10.0.0.1")));
+        assert!(!deny_first.is_allowed(&ip("192.168.0.1")));
+
+        let allow_first = AccessList::new(
+            "test",
+            &Access {
+                order: Some("allow_first".to_string()),
+                ..config
+            },
+        )
+        .unwrap();
+        assert!(allow_first.is_allowed(&ip("10.0.0.1")));
+
+        let deny_only = AccessList::new(
+            "test",
+            &Access {
+                deny: Some(vec!["10.0.0.1".to_string()]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(deny_only.is_allowed(&ip("192.168.0.1")));
+        assert!(!deny_only.is_allowed(&ip("10.0.0.1")));
+    }
+}
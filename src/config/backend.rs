@@ -1,28 +1,147 @@
-use super::store::BackendType;
+use super::{
+    discovery::{FileDiscovery, HttpDiscovery},
+    proxy::{HealthCheck, Upstream},
+    selection::{BackendStats, BackupBackend, Ketama, LeastConnections, PeakEwma},
+    store::BackendType,
+};
 use crate::errors::Errors;
 use http::Extensions;
 use pingora::{
     lb::{
-        discovery,
+        discovery::{self, ServiceDiscovery},
+        health_check::{self, TcpHealthCheck},
         selection::{
             algorithms::{Random, RoundRobin},
-            consistent::KetamaHashing,
             weighted::Weighted,
         },
         Backend, Backends, LoadBalancer,
     },
     prelude::HttpPeer,
-    protocols::l4::socket::SocketAddr,
+    protocols::l4::{ext::TcpKeepalive, socket::SocketAddr},
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    time::Duration,
 };
-use std::{collections::BTreeSet, sync::Arc};
 
-pub async fn load_backend(
-    svc: &crate::config::proxy::Service,
-    endpoints: &Vec<crate::config::proxy::Endpoint>,
-) -> Result<BackendType, Errors> {
+// tags and metadata of an endpoint, e.g. from a consul catalog
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EndpointMetadata {
+    pub tags: Vec<String>,
+    pub metadata: BTreeMap<String, String>,
+}
+
+// bound every phase of an upstream attempt: connecting including the tls handshake, and each
+// write and read, so a stalled attempt fails within the timeout and can be retried.
+// Smaller timeouts of the `upstream` options are kept.
+pub fn apply_per_try_timeout(peer: &mut HttpPeer, timeout: Duration) {
+    let options = &mut peer.options;
+    for value in [
+        &mut options.connection_timeout,
+        &mut options.total_connection_timeout,
+        &mut options.read_timeout,
+        &mut options.write_timeout,
+    ] {
+        *value = Some(value.map_or(timeout, |v| v.min(timeout)));
+    }
+}
+
+// Marks a backend that expects a PROXY protocol header in `Backend::ext`
+#[derive(Debug, Clone)]
+pub struct SendProxyProtocol;
+
+// map the connection options onto the peer
+fn apply_upstream(peer: &mut HttpPeer, upstream: &Upstream) {
+    let ms = |v: Option<u64>| v.map(Duration::from_millis);
+    if let Some(v) = ms(upstream.connect_timeout_ms) {
+        peer.options.connection_timeout = Some(v);
+    }
+    if let Some(v) = ms(upstream.total_connection_timeout_ms) {
+        peer.options.total_connection_timeout = Some(v);
+    }
+    if let Some(v) = ms(upstream.read_timeout_ms) {
+        peer.options.read_timeout = Some(v);
+    }
+    if let Some(v) = ms(upstream.write_timeout_ms) {
+        peer.options.write_timeout = Some(v);
+    }
+    if let Some(v) = ms(upstream.idle_timeout_ms) {
+        peer.options.idle_timeout = Some(v);
+    }
+    if let Some(keepalive) = &upstream.tcp_keepalive {
+        peer.options.tcp_keepalive = Some(TcpKeepalive {
+            idle: Duration::from_millis(keepalive.idle_ms),
+            interval: Duration::from_millis(keepalive.interval_ms),
+            count: keepalive.count,
+        });
+    }
+    if let Some(v) = upstream.tcp_fast_open {
+        peer.options.tcp_fast_open = v;
+    }
+}
+
+fn health_check(
+    svc_name: &str,
+    check: &HealthCheck,
+) -> Result<Box<dyn health_check::HealthCheck + Send + Sync>, Errors> {
+    match check.check_type.as_str() {
+        "tcp" => {
+            let mut hc = TcpHealthCheck::new();
+            if let Some(v) = check.consecutive_success {
+                hc.consecutive_success = v;
+            }
+            if let Some(v) = check.consecutive_failure {
+                hc.consecutive_failure = v;
+            }
+            Ok(hc)
+        }
+        "http" => {
+            let mut hc = health_check::HttpHealthCheck::new(
+                check.host.as_deref().unwrap_or("localhost"),
+                false,
+            );
+            if let Some(path) = &check.path {
+                let uri = path.parse::<http::Uri>().map_err(|e| {
+                    Errors::ConfigError(format!(
+                        "Invalid health check path of service {}: {}",
+                        svc_name, e
+                    ))
+                })?;
+                hc.req.set_uri(uri);
+            }
+            if let Some(v) = check.consecutive_success {
+                hc.consecutive_success = v;
+            }
+            if let Some(v) = check.consecutive_failure {
+                hc.consecutive_failure = v;
+            }
+            Ok(Box::new(hc))
+        }
+        _ => Err(Errors::ConfigError(format!(
+            "Unknown health check type: {}",
+            check.check_type
+        ))),
+    }
+}
+
+pub fn build_backends(
+    svc_name: &str,
+    endpoints: &[crate::config::proxy::Endpoint],
+    upstream: Option<&Upstream>,
+) -> Result<BTreeSet<Backend>, Errors> {
     let mut backends: BTreeSet<Backend> = BTreeSet::new();
     for e in endpoints {
-        let endpoint = format!("{}:{}", e.ip, e.port);
+        let endpoint = match (&e.unix, &e.ip, e.port) {
+            (Some(path), _, _) => format!("unix:{}", path),
+            (None, Some(ip), Some(port)) => format!("{}:{}", ip, port),
+            _ => {
+                return Err(Errors::ConfigError(format!(
+                    "Endpoint of service {} requires either `unix` or `ip` and `port`",
+                    svc_name
+                )));
+            }
+        };
         let addr: SocketAddr = match endpoint.parse() {
             Ok(val) => val,
             Err(e) => {
@@ -37,21 +156,77 @@ pub async fn load_backend(
             weight: e.weight.unwrap_or(1) as usize,
             ext: Extensions::new(),
         };
-        if backend
-            .ext
-            .insert::<HttpPeer>(HttpPeer::new(endpoint, false, String::new()))
-            .is_some()
-        {
+        let mut peer = match &e.unix {
+            Some(path) => HttpPeer::new_uds(path, false, String::new()).map_err(|err| {
+                Errors::ConfigError(format!("Unable to create unix socket peer: {}", err))
+            })?,
+            None => HttpPeer::new(endpoint, false, String::new()),
+        };
+        let upstream = match (upstream, &e.upstream) {
+            (Some(svc), Some(endpoint)) => svc.merge(endpoint),
+            (svc, endpoint) => svc.or(endpoint.as_ref()).cloned().unwrap_or_default(),
+        };
+        apply_upstream(&mut peer, &upstream);
+        if backend.ext.insert::<HttpPeer>(peer).is_some() {
             return Err(Errors::ConfigError("Unable to insert HttpPeer".to_string()));
         }
+        backend
+            .ext
+            .insert::<Arc<BackendStats>>(Arc::new(BackendStats::with_max_in_flight(
+                upstream.max_connections.unwrap_or(0),
+            )));
+        if e.tags.is_some() || e.metadata.is_some() {
+            backend.ext.insert::<EndpointMetadata>(EndpointMetadata {
+                tags: e.tags.clone().unwrap_or_default(),
+                metadata: e.metadata.clone().unwrap_or_default(),
+            });
+        }
+        if e.backup.unwrap_or(false) {
+            backend.ext.insert::<BackupBackend>(BackupBackend);
+        }
+        if upstream.proxy_protocol.unwrap_or(false) {
+            backend.ext.insert::<SendProxyProtocol>(SendProxyProtocol);
+        }
         backends.insert(backend);
     }
-    let disco = discovery::Static::new(backends);
+    Ok(backends)
+}
+
+pub async fn load_backend(
+    svc: &crate::config::proxy::Service,
+    endpoints: &[crate::config::proxy::Endpoint],
+) -> Result<BackendType, Errors> {
+    let disco: Box<dyn ServiceDiscovery + Send + Sync> = match &svc.discovery {
+        Some(d) => match d.discovery_type.as_str() {
+            "file" => {
+                let Some(path) = &d.path else {
+                    return Err(Errors::ConfigError(format!(
+                        "File discovery of service {} requires a path",
+                        svc.name
+                    )));
+                };
+                FileDiscovery::new(&svc.name, path, svc.upstream.clone())
+            }
+            "http" => HttpDiscovery::new(&svc.name, d, svc.upstream.clone())?,
+            _ => {
+                return Err(Errors::ConfigError(format!(
+                    "Unknown discovery type: {}",
+                    d.discovery_type
+                )));
+            }
+        },
+        None => {
+            discovery::Static::new(build_backends(&svc.name, endpoints, svc.upstream.as_ref())?)
+        }
+    };
+    let mut backends = Backends::new(disco);
+    if let Some(check) = &svc.health_check {
+        backends.set_health_check(health_check(&svc.name, check)?);
+    }
     // Initialize the appropriate iterator based on the algorithm
     let backend_type = match svc.algorithm.as_str() {
         "round_robin" => {
-            let upstreams =
-                LoadBalancer::<Weighted<RoundRobin>>::from_backends(Backends::new(disco));
+            let upstreams = LoadBalancer::<Weighted<RoundRobin>>::from_backends(backends);
             match upstreams.update().await {
                 Ok(_) => {}
                 Err(e) => {
@@ -61,8 +236,7 @@ pub async fn load_backend(
             BackendType::RoundRobin(Arc::new(upstreams))
         }
         "weighted" => {
-            let backend =
-                LoadBalancer::<Weighted<fnv::FnvHasher>>::from_backends(Backends::new(disco));
+            let backend = LoadBalancer::<Weighted<fnv::FnvHasher>>::from_backends(backends);
             match backend.update().await {
                 Ok(_) => {}
                 Err(e) => {
@@ -72,7 +246,7 @@ pub async fn load_backend(
             BackendType::Weighted(Arc::new(backend))
         }
         "consistent" => {
-            let backend = LoadBalancer::<KetamaHashing>::from_backends(Backends::new(disco));
+            let backend = LoadBalancer::<Ketama>::from_backends(backends);
             match backend.update().await {
                 Ok(_) => {}
                 Err(e) => {
@@ -82,7 +256,7 @@ pub async fn load_backend(
             BackendType::Consistent(Arc::new(backend))
         }
         "random" => {
-            let upstreams = LoadBalancer::<Weighted<Random>>::from_backends(Backends::new(disco));
+            let upstreams = LoadBalancer::<Weighted<Random>>::from_backends(backends);
             match upstreams.update().await {
                 Ok(_) => {}
                 Err(e) => {
@@ -91,6 +265,26 @@ pub async fn load_backend(
             }
             BackendType::Random(Arc::new(upstreams))
         }
+        "least_conn" => {
+            let upstreams = LoadBalancer::<LeastConnections>::from_backends(backends);
+            match upstreams.update().await {
+                Ok(_) => {}
+                Err(e) => {
+                    return Err(Errors::PingoraError(format!("{}", e)));
+                }
+            }
+            BackendType::LeastConn(Arc::new(upstreams))
+        }
+        "p2c_ewma" => {
+            let upstreams = LoadBalancer::<PeakEwma>::from_backends(backends);
+            match upstreams.update().await {
+                Ok(_) => {}
+                Err(e) => {
+                    return Err(Errors::PingoraError(format!("{}", e)));
+                }
+            }
+            BackendType::PeakEwma(Arc::new(upstreams))
+        }
         _ => {
             return Err(Errors::ConfigError(format!(
                 "Unknown algorithm: {}",
@@ -100,3 +294,69 @@ pub async fn load_backend(
     };
     Ok(backend_type)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::proxy::Service;
+
+    fn service(algorithm: &str) -> Service {
+        let config = format!(
+            r#"
+name: test
+type: http
+algorithm: {}
+endpoints:
+  - ip: 127.0.0.1
+    port: 3000
+  - unix: /run/easy-proxy-test.sock
+"#,
+            algorithm
+        );
+        serde_yml::from_str(&config).unwrap()
+    }
+
+    #[test]
+    fn test_per_try_timeout() {
+        let mut peer = HttpPeer::new("127.0.0.1:3000", false, String::new());
+        apply_upstream(
+            &mut peer,
+            &Upstream {
+                connect_timeout_ms: Some(500),
+                read_timeout_ms: Some(30000),
+                ..Default::default()
+            },
+        );
+        apply_per_try_timeout(&mut peer, Duration::from_secs(2));
+        let ms = |v: Option<Duration>| v.map(|v| v.as_millis());
+        assert_eq!(ms(peer.options.connection_timeout), Some(500));
+        assert_eq!(ms(peer.options.total_connection_timeout), Some(2000));
+        assert_eq!(ms(peer.options.read_timeout), Some(2000));
+        assert_eq!(ms(peer.options.write_timeout), Some(2000));
+    }
+
+    #[tokio::test]
+    async fn test_unix_endpoints() {
+        for algorithm in [
+            "round_robin",
+            "weighted",
+            "consistent",
+            "random",
+            "least_conn",
+            "p2c_ewma",
+        ] {
+            let svc = service(algorithm);
+            assert!(load_backend(&svc, &svc.endpoints).await.is_ok());
+        }
+        // the consistent ring is keyed by the address, the unix socket gets its share of keys
+        let svc = service("consistent");
+        let BackendType::Consistent(lb) = load_backend(&svc, &svc.endpoints).await.unwrap() else {
+            panic!("consistent backend expected");
+        };
+        let selected: BTreeSet<String> = (0..64)
+            .filter_map(|i| lb.select(format!("client-{}", i).as_bytes(), 256))
+            .map(|b| b.addr.to_string())
+            .collect();
+        assert_eq!(selected.len(), 2);
+    }
+}
@@ -0,0 +1,182 @@
+use super::proxy::BasicAuth as BasicAuthConfig;
+use crate::errors::Errors;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+// verified credentials kept to skip bcrypt on every request
+static VERIFIED_MAX: usize = 1024;
+
+#[derive(Debug)]
+enum PasswordHash {
+    Bcrypt(String),
+    // `{SHA}` base64 of the sha1 digest
+    Sha1(Vec<u8>),
+}
+
+impl PasswordHash {
+    fn parse(hash: &str) -> Result<Self, Errors> {
+        if hash.starts_with("$2y$") || hash.starts_with("$2b$") || hash.starts_with("$2a$") {
+            return Ok(PasswordHash::Bcrypt(hash.to_string()));
+        }
+        if let Some(sha) = hash.strip_prefix("{SHA}") {
+            return BASE64_STANDARD
+                .decode(sha)
+                .map(PasswordHash::Sha1)
+                .map_err(|e| Errors::ConfigError(format!("Invalid {{SHA}} password: {}", e)));
+        }
+        Err(Errors::ConfigError(
+            "Unsupported password hash, use bcrypt or {SHA}".to_string(),
+        ))
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            PasswordHash::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            PasswordHash::Sha1(digest) => {
+                let actual = ring::digest::digest(
+                    &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+                    password.as_bytes(),
+                );
+                // compare digests of the digests so the time doesn't depend on the content
+                Sha256::digest(actual.as_ref()) == Sha256::digest(digest)
+            }
+        }
+    }
+}
+
+// Basic authentication against htpasswd entries
+#[derive(Debug)]
+pub struct BasicAuth {
+    pub realm: String,
+    pub strip_authorization: bool,
+    users: HashMap<String, PasswordHash>,
+    verified: Mutex<HashSet<[u8; 32]>>,
+}
+
+// `user:hash` lines, `#` starts a comment
+fn parse_htpasswd(data: &str, users: &mut HashMap<String, PasswordHash>) -> Result<(), Errors> {
+    for line in data.lines().map(|l| l.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((user, hash)) = line.split_once(':') else {
+            return Err(Errors::ConfigError(format!(
+                "Invalid htpasswd entry: {}",
+                line
+            )));
+        };
+        let hash = PasswordHash::parse(hash).map_err(|e| {
+            Errors::ConfigError(format!("Invalid htpasswd entry for {}: {}", user, e))
+        })?;
+        users.insert(user.to_string(), hash);
+    }
+    Ok(())
+}
+
+impl BasicAuth {
+    pub fn new(route: &str, config: &BasicAuthConfig) -> Result<Self, Errors> {
+        let mut users = HashMap::new();
+        if let Some(path) = &config.htpasswd {
+            let data = std::fs::read_to_string(path).map_err(|e| {
+                Errors::ConfigError(format!("Unable to read htpasswd file {}: {}", path, e))
+            })?;
+            parse_htpasswd(&data, &mut users)?;
+        }
+        parse_htpasswd(
+            &config.users.clone().unwrap_or_default().join("\n"),
+            &mut users,
+        )?;
+        if users.is_empty() {
+            return Err(Errors::ConfigError(format!(
+                "Basic auth of route {} has no users",
+                route
+            )));
+        }
+        Ok(Self {
+            realm: config.realm.clone().unwrap_or_else(|| route.to_string()),
+            strip_authorization: config.strip_authorization.unwrap_or(false),
+            users,
+            verified: Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn verified(&self) -> std::sync::MutexGuard<'_, HashSet<[u8; 32]>> {
+        match self.verified.lock() {
+            Ok(val) => val,
+            Err(e) => e.into_inner(),
+        }
+    }
+
+    pub fn challenge(&self) -> String {
+        format!("Basic realm=\"{}\"", self.realm.replace('"', "'"))
+    }
+
+    // the user of a valid `Authorization` header
+    pub fn authenticate(&self, authorization: &str) -> Option<String> {
+        let (scheme, credentials) = authorization.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = BASE64_STANDARD.decode(credentials.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+        let hash = self.users.get(user)?;
+
+        let key: [u8; 32] = Sha256::digest(decoded.as_bytes()).into();
+        if self.verified().contains(&key) {
+            return Some(user.to_string());
+        }
+        if !hash.verify(password) {
+            return None;
+        }
+        let mut verified = self.verified();
+        if verified.len() >= VERIFIED_MAX {
+            verified.clear();
+        }
+        verified.insert(key);
+        Some(user.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_basic_auth() {
+        let auth = BasicAuth::new(
+            "test",
+            &BasicAuthConfig {
+                users: Some(vec![
+                    // password: secret
+                    "alice:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=".to_string(),
+                    format!("bob:{}", bcrypt::hash("hunter2", 4).unwrap()),
+                ]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let header = |cred: &str| format!("Basic {}", BASE64_STANDARD.encode(cred));
+        assert_eq!(
+            auth.authenticate(&header("alice:secret")),
+            Some("alice".to_string())
+        );
+        assert_eq!(auth.authenticate(&header("alice:wrong")), None);
+        assert_eq!(
+            auth.authenticate(&header("bob:hunter2")),
+            Some("bob".to_string())
+        );
+        // cached
+        assert_eq!(
+            auth.authenticate(&header("bob:hunter2")),
+            Some("bob".to_string())
+        );
+        assert_eq!(auth.authenticate(&header("carol:secret")), None);
+        assert_eq!(auth.authenticate("Bearer abc"), None);
+        assert_eq!(auth.challenge(), "Basic realm=\"test\"");
+    }
+}
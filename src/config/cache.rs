@@ -0,0 +1,262 @@
+use super::{proxy::Cache, runtime};
+use crate::errors::Errors;
+use pingora::{
+    cache::{
+        cache_control::CacheControl,
+        eviction::{lru::Manager, EvictionManager},
+        filters::resp_cacheable,
+        key::HashBinary,
+        lock::CacheLock,
+        trace::Span,
+        CacheKey, CacheMeta, CacheMetaDefaults, CachePhase, MemCache, NoCacheReason, RespCacheable,
+        Storage, VarianceBuilder,
+    },
+    http::{RequestHeader, ResponseHeader},
+    ErrorSource,
+};
+use std::{
+    sync::LazyLock,
+    time::{Duration, SystemTime},
+};
+
+static DEFAULT_MAX_SIZE: usize = 128 * 1024 * 1024;
+static DEFAULT_LOCK_TIMEOUT_MS: u64 = 2000;
+// statuses cached with a route ttl, the same ones are heuristically cacheable in RFC 9111
+static TTL_STATUSES: [u16; 8] = [200, 203, 204, 300, 301, 308, 404, 410];
+
+// shared by all routes, the LRU evicts the least recently used entries over `max_size`
+pub static STORAGE: LazyLock<MemCache> = LazyLock::new(MemCache::new);
+pub static EVICTION: LazyLock<Manager<16>> = LazyLock::new(|| {
+    let max_size = runtime::config()
+        .cache
+        .as_ref()
+        .and_then(|c| c.max_size)
+        .unwrap_or(DEFAULT_MAX_SIZE);
+    Manager::with_capacity(max_size, 8192)
+});
+// concurrent misses of a key wait for the first one instead of going upstream
+pub static LOCK: LazyLock<CacheLock> = LazyLock::new(|| {
+    let timeout = runtime::config()
+        .cache
+        .as_ref()
+        .and_then(|c| c.lock_timeout_ms)
+        .unwrap_or(DEFAULT_LOCK_TIMEOUT_MS);
+    CacheLock::new(Duration::from_millis(timeout))
+});
+
+pub fn key(primary: &str) -> CacheKey {
+    CacheKey::new("", primary, "")
+}
+
+// `[<header selector>@]<scheme>://<host><uri>`, routes selected by header don't share
+// entries with each other or with the routes of the host
+pub fn default_key(header_selector: &str, scheme: &str, host: &str, uri: &str) -> String {
+    if header_selector.is_empty() {
+        format!("{}://{}{}", scheme, host, uri)
+    } else {
+        format!("{}@{}://{}{}", header_selector, scheme, host, uri)
+    }
+}
+
+// `X-Cache` value
+pub fn status(phase: CachePhase) -> &'static str {
+    match phase {
+        CachePhase::Hit => "HIT",
+        CachePhase::Miss => "MISS",
+        CachePhase::Stale => "STALE",
+        CachePhase::Expired => "EXPIRED",
+        CachePhase::Revalidated | CachePhase::RevalidatedNoCache(_) => "REVALIDATED",
+        _ => "BYPASS",
+    }
+}
+
+// `Cache-Control` / `Expires` of the upstream, or the route ttl when set
+pub fn response_cacheable(
+    cache: &Cache,
+    resp: &ResponseHeader,
+    authorization: bool,
+) -> RespCacheable {
+    let cc = CacheControl::from_resp_headers(resp);
+    let stale_while_revalidate = cache.stale_while_revalidate.unwrap_or(0);
+    let stale_if_error = cache.stale_if_error.unwrap_or(0);
+    // `Vary: *` never matches a later request
+    if vary(resp.headers.get_all("vary").iter()).any(|v| v == "*") {
+        return RespCacheable::Uncacheable(NoCacheReason::OriginNotCache);
+    }
+    // the route ttl doesn't apply to responses personal to a client: requests with credentials
+    // the upstream didn't mark as shared, and responses that set cookies
+    let personal = resp.headers.contains_key("set-cookie")
+        || (authorization
+            && !cc
+                .as_ref()
+                .is_some_and(|cc| cc.allow_caching_authorized_req()));
+    let Some(ttl) = cache.ttl.filter(|_| !personal) else {
+        let defaults = CacheMetaDefaults::new(|_| None, stale_while_revalidate, stale_if_error);
+        return resp_cacheable(cc.as_ref(), resp.clone(), authorization, &defaults);
+    };
+    let forbidden = cc.as_ref().is_some_and(|cc| cc.no_store() || cc.private());
+    if forbidden || !TTL_STATUSES.contains(&resp.status.as_u16()) {
+        return RespCacheable::Uncacheable(NoCacheReason::OriginNotCache);
+    }
+    let now = SystemTime::now();
+    RespCacheable::Cacheable(CacheMeta::new(
+        now + Duration::from_secs(ttl),
+        now,
+        stale_while_revalidate,
+        stale_if_error,
+        resp.clone(),
+    ))
+}
+
+// stale entries are served while they're refreshed within `stale_while_revalidate`,
+// and instead of upstream errors within `stale_if_error`
+pub fn serve_stale(meta: &CacheMeta, error: Option<&pingora::Error>, now: SystemTime) -> bool {
+    match error {
+        None => meta.serve_stale_while_revalidate(now),
+        Some(e) => e.esource() == &ErrorSource::Upstream && meta.serve_stale_if_error(now),
+    }
+}
+
+// variance of the request for the headers listed in `Vary`
+pub fn variance(meta: &CacheMeta, req: &RequestHeader) -> Option<HashBinary> {
+    let names: Vec<String> = vary(meta.headers().get_all("vary").iter()).collect();
+    if names.is_empty() {
+        return None;
+    }
+    let mut variance = VarianceBuilder::new();
+    for name in names.iter() {
+        let value = req
+            .headers
+            .get(name.as_str())
+            .map(|v| v.as_bytes())
+            .unwrap_or_default();
+        variance.add_value(name, value);
+    }
+    variance.finalize()
+}
+
+fn vary<'a>(
+    values: impl Iterator<Item = &'a http::HeaderValue> + 'a,
+) -> impl Iterator<Item = String> + 'a {
+    values
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_ascii_lowercase())
+        .filter(|v| !v.is_empty())
+}
+
+// remove an entry by its primary key, e.g. `https://example.com/index.html`
+// variants of a `Vary` response are kept until they expire or are evicted
+pub async fn purge(primary: &str) -> Result<bool, Errors> {
+    let key = key(primary).to_compact();
+    EVICTION.remove(&key);
+    let storage: &'static MemCache = &STORAGE;
+    storage
+        .purge(&key, &Span::inactive().handle())
+        .await
+        .map_err(|e| Errors::ProxyError(format!("Unable to purge {}: {}", primary, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_response_cacheable() {
+        let cache = Cache {
+            ttl: Some(60),
+            ..Default::default()
+        };
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        assert!(matches!(
+            response_cacheable(&cache, &resp, false),
+            RespCacheable::Cacheable(_)
+        ));
+        resp.insert_header("cache-control", "private").unwrap();
+        assert!(matches!(
+            response_cacheable(&cache, &resp, false),
+            RespCacheable::Uncacheable(_)
+        ));
+
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("vary", "Accept-Encoding, *").unwrap();
+        assert!(matches!(
+            response_cacheable(&cache, &resp, false),
+            RespCacheable::Uncacheable(_)
+        ));
+
+        // without a route ttl the upstream decides
+        let resp = ResponseHeader::build(200, None).unwrap();
+        assert!(matches!(
+            response_cacheable(&Cache::default(), &resp, false),
+            RespCacheable::Uncacheable(_)
+        ));
+    }
+
+    #[test]
+    fn test_personal_responses() {
+        let cache = Cache {
+            ttl: Some(60),
+            ..Default::default()
+        };
+        // requests with credentials only when the upstream allows sharing them
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        assert!(matches!(
+            response_cacheable(&cache, &resp, true),
+            RespCacheable::Uncacheable(_)
+        ));
+        resp.insert_header("cache-control", "public").unwrap();
+        assert!(matches!(
+            response_cacheable(&cache, &resp, true),
+            RespCacheable::Cacheable(_)
+        ));
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("cache-control", "s-maxage=60").unwrap();
+        assert!(matches!(
+            response_cacheable(&cache, &resp, true),
+            RespCacheable::Cacheable(_)
+        ));
+
+        // the cookie of one client is never served to another
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("set-cookie", "session=abc").unwrap();
+        assert!(matches!(
+            response_cacheable(&cache, &resp, false),
+            RespCacheable::Uncacheable(_)
+        ));
+    }
+
+    #[test]
+    fn test_serve_stale() {
+        let now = SystemTime::now();
+        let resp = ResponseHeader::build(200, None).unwrap();
+        // expired 5 seconds ago, 10 seconds to revalidate, no stale-if-error
+        let meta = CacheMeta::new(
+            now - Duration::from_secs(5),
+            now - Duration::from_secs(65),
+            10,
+            0,
+            resp,
+        );
+        assert!(serve_stale(&meta, None, now));
+        assert!(!serve_stale(&meta, None, now + Duration::from_secs(10)));
+        let error = pingora::Error::new_up(pingora::ErrorType::ConnectRefused);
+        assert!(!serve_stale(&meta, Some(&error), now));
+    }
+
+    #[test]
+    fn test_default_key() {
+        assert_eq!(
+            default_key("", "https", "example.com", "/a?b=1"),
+            "https://example.com/a?b=1"
+        );
+        assert_ne!(
+            default_key("", "http", "example.com", "/a"),
+            default_key("", "https", "example.com", "/a")
+        );
+        assert_eq!(
+            default_key("api", "http", "example.com", "/a"),
+            "api@http://example.com/a"
+        );
+    }
+}
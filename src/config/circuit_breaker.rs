@@ -0,0 +1,362 @@
+use super::proxy::CircuitBreaker;
+use crate::errors::Errors;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+// number of buckets in the rolling window
+const BUCKETS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl fmt::Display for BreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BreakerState::Closed => write!(f, "closed"),
+            BreakerState::Open => write!(f, "open"),
+            BreakerState::HalfOpen => write!(f, "half_open"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: BreakerState,
+    opened_at: Instant,
+    consecutive_failures: u32,
+    // (bucket index since `started`, requests, failures)
+    buckets: [(u64, u32, u32); BUCKETS],
+    half_open_in_flight: u32,
+    half_open_successes: u32,
+}
+
+// Per service circuit breaker: closed -> open on failures, open -> half-open after
+// `open_duration`, half-open -> closed when the trial requests succeed.
+#[derive(Debug)]
+pub struct Breaker {
+    service: String,
+    consecutive_failures: Option<u32>,
+    error_rate: Option<f64>,
+    min_requests: u32,
+    bucket: Duration,
+    open_duration: Duration,
+    half_open_requests: u32,
+    max_concurrent: Option<usize>,
+    pub statuses: Vec<u16>,
+    pub status: u16,
+    pub message: String,
+    started: Instant,
+    in_flight: AtomicUsize,
+    inner: Mutex<Inner>,
+}
+
+impl Breaker {
+    pub fn new(service: &str, config: &CircuitBreaker) -> Result<Self, Errors> {
+        if config.consecutive_failures.is_none()
+            && config.error_rate.is_none()
+            && config.max_concurrent.is_none()
+        {
+            return Err(Errors::ConfigError(format!(
+                "Circuit breaker of service {} requires consecutive_failures, error_rate or max_concurrent",
+                service
+            )));
+        }
+        if config.error_rate.is_some_and(|r| r <= 0.0 || r > 1.0) {
+            return Err(Errors::ConfigError(format!(
+                "Invalid error_rate for service {}, must be between 0 and 1",
+                service
+            )));
+        }
+        let window = Duration::from_secs(config.window.unwrap_or(10).max(1));
+        Ok(Self {
+            service: service.to_string(),
+            consecutive_failures: config.consecutive_failures,
+            error_rate: config.error_rate,
+            min_requests: config.min_requests.unwrap_or(20),
+            bucket: window / BUCKETS as u32,
+            open_duration: Duration::from_secs(config.open_duration.unwrap_or(30)),
+            half_open_requests: config.half_open_requests.unwrap_or(1).max(1),
+            max_concurrent: config.max_concurrent,
+            statuses: config
+                .statuses
+                .clone()
+                .unwrap_or_else(|| (500..600).collect()),
+            status: config.status.unwrap_or(503),
+            message: config
+                .message
+                .clone()
+                .unwrap_or_else(|| "Service temporarily unavailable".to_string()),
+            started: Instant::now(),
+            in_flight: AtomicUsize::new(0),
+            inner: Mutex::new(Inner {
+                state: BreakerState::Closed,
+                opened_at: Instant::now(),
+                consecutive_failures: 0,
+                buckets: [(0, 0, 0); BUCKETS],
+                half_open_in_flight: 0,
+                half_open_successes: 0,
+            }),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        match self.inner.lock() {
+            Ok(val) => val,
+            Err(e) => e.into_inner(),
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        let inner = self.lock();
+        if inner.state == BreakerState::Open && inner.opened_at.elapsed() >= self.open_duration {
+            return BreakerState::HalfOpen;
+        }
+        inner.state
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    // returns `None` when the request must be rejected, the permit is released when dropped
+    // unless the outcome of the request is recorded with it
+    pub fn acquire(&self) -> Option<Permit<'_>> {
+        // reserve the slot in a single step so concurrent requests can't exceed max_concurrent
+        self.in_flight
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| {
+                match self.max_concurrent {
+                    Some(max) if v >= max => None,
+                    _ => Some(v + 1),
+                }
+            })
+            .ok()?;
+        let mut inner = self.lock();
+        if inner.state == BreakerState::Open {
+            if inner.opened_at.elapsed() < self.open_duration {
+                drop(inner);
+                self.finish();
+                return None;
+            }
+            tracing::info!("Circuit breaker of service {} is half-open", self.service);
+            inner.state = BreakerState::HalfOpen;
+            inner.half_open_in_flight = 0;
+            inner.half_open_successes = 0;
+        }
+        if inner.state == BreakerState::HalfOpen {
+            if inner.half_open_in_flight >= self.half_open_requests {
+                drop(inner);
+                self.finish();
+                return None;
+            }
+            inner.half_open_in_flight += 1;
+        }
+        Some(Permit {
+            breaker: self,
+            recorded: false,
+        })
+    }
+
+    // the request ended without an outcome, e.g. it was answered by the proxy
+    fn release(&self) {
+        self.finish();
+        let mut inner = self.lock();
+        if inner.state == BreakerState::HalfOpen {
+            inner.half_open_in_flight = inner.half_open_in_flight.saturating_sub(1);
+        }
+    }
+
+    fn record(&self, success: bool) {
+        self.finish();
+        let mut inner = self.lock();
+        match inner.state {
+            BreakerState::Open => {}
+            BreakerState::HalfOpen => {
+                inner.half_open_in_flight = inner.half_open_in_flight.saturating_sub(1);
+                if !success {
+                    self.open(&mut inner);
+                    return;
+                }
+                inner.half_open_successes += 1;
+                if inner.half_open_successes >= self.half_open_requests {
+                    tracing::info!("Circuit breaker of service {} is closed", self.service);
+                    inner.state = BreakerState::Closed;
+                    inner.consecutive_failures = 0;
+                    inner.buckets = [(0, 0, 0); BUCKETS];
+                }
+            }
+            BreakerState::Closed => {
+                let index =
+                    (self.started.elapsed().as_nanos() / self.bucket.as_nanos().max(1)) as u64;
+                let bucket = &mut inner.buckets[index as usize % BUCKETS];
+                if bucket.0 != index {
+                    *bucket = (index, 0, 0);
+                }
+                bucket.1 += 1;
+                if success {
+                    inner.consecutive_failures = 0;
+                    return;
+                }
+                bucket.2 += 1;
+                inner.consecutive_failures += 1;
+                let (requests, failures) = inner
+                    .buckets
+                    .iter()
+                    .filter(|b| b.0 + (BUCKETS as u64) > index)
+                    .fold((0, 0), |acc, b| (acc.0 + b.1, acc.1 + b.2));
+                let tripped = self
+                    .consecutive_failures
+                    .is_some_and(|max| inner.consecutive_failures >= max)
+                    || self.error_rate.is_some_and(|rate| {
+                        requests >= self.min_requests && failures as f64 / requests as f64 >= rate
+                    });
+                if tripped {
+                    self.open(&mut inner);
+                }
+            }
+        }
+    }
+
+    fn open(&self, inner: &mut Inner) {
+        tracing::warn!("Circuit breaker of service {} is open", self.service);
+        inner.state = BreakerState::Open;
+        inner.opened_at = Instant::now();
+    }
+
+    fn finish(&self) {
+        let _ = self
+            .in_flight
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| v.checked_sub(1));
+    }
+}
+
+// A request admitted by a breaker
+pub struct Permit<'a> {
+    breaker: &'a Breaker,
+    recorded: bool,
+}
+
+impl Permit<'_> {
+    pub fn breaker(&self) -> &Breaker {
+        self.breaker
+    }
+
+    pub fn record(mut self, success: bool) {
+        self.recorded = true;
+        self.breaker.record(success);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if !self.recorded {
+            self.breaker.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consecutive_failures() {
+        let breaker = Breaker::new(
+            "test",
+            &CircuitBreaker {
+                consecutive_failures: Some(2),
+                open_duration: Some(0),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        breaker.acquire().unwrap().record(false);
+        breaker.acquire().unwrap().record(true);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        for _ in 0..2 {
+            breaker.acquire().unwrap().record(false);
+        }
+        // open_duration 0: the next request is a half-open trial
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        let probe = breaker.acquire().unwrap();
+        assert!(breaker.acquire().is_none());
+        probe.record(true);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert_eq!(breaker.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_dropped_probe() {
+        let breaker = Breaker::new(
+            "test",
+            &CircuitBreaker {
+                consecutive_failures: Some(1),
+                open_duration: Some(0),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        breaker.acquire().unwrap().record(false);
+        // a probe that ends without an outcome, e.g. on an error before `logging`
+        let probe = breaker.acquire().unwrap();
+        assert!(breaker.acquire().is_none());
+        drop(probe);
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert_eq!(breaker.in_flight(), 0);
+        breaker.acquire().unwrap().record(true);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn test_error_rate_and_concurrency() {
+        let breaker = Breaker::new(
+            "test",
+            &CircuitBreaker {
+                error_rate: Some(0.5),
+                min_requests: Some(4),
+                max_concurrent: Some(4),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        for success in [true, false, true] {
+            breaker.acquire().unwrap().record(success);
+        }
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        breaker.acquire().unwrap().record(false);
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(breaker.acquire().is_none());
+        assert_eq!(breaker.in_flight(), 0);
+
+        let breaker = Breaker::new(
+            "test",
+            &CircuitBreaker {
+                max_concurrent: Some(2),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        // concurrent requests never get past max_concurrent
+        let permits = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8).map(|_| scope.spawn(|| breaker.acquire())).collect();
+            handles
+                .into_iter()
+                .filter_map(|h| h.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(permits.len(), 2);
+        assert_eq!(breaker.in_flight(), 2);
+        drop(permits);
+        assert_eq!(breaker.in_flight(), 0);
+        assert!(breaker.acquire().is_some());
+    }
+}
@@ -0,0 +1,210 @@
+use super::proxy::Cors;
+use crate::errors::Errors;
+use regex::Regex;
+
+static DEFAULT_METHODS: [&str; 3] = ["GET", "HEAD", "POST"];
+
+#[derive(Debug)]
+enum Origin {
+    Any,
+    Exact(String),
+    // `*` in an origin matches one or more characters, e.g. `https://*.example.com`
+    Wildcard(String, String),
+    Regex(Regex),
+}
+
+impl Origin {
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            Origin::Any => true,
+            Origin::Exact(o) => o.eq_ignore_ascii_case(origin),
+            Origin::Wildcard(prefix, suffix) => {
+                let origin = origin.to_ascii_lowercase();
+                origin.len() > prefix.len() + suffix.len()
+                    && origin.starts_with(prefix.as_str())
+                    && origin.ends_with(suffix.as_str())
+            }
+            Origin::Regex(r) => r.is_match(origin),
+        }
+    }
+}
+
+// Per route CORS policy, preflight requests are answered by the proxy
+#[derive(Debug)]
+pub struct CorsPolicy {
+    origins: Vec<Origin>,
+    methods: Vec<String>,
+    // `None` reflects the requested headers
+    headers: Option<String>,
+    expose_headers: Option<String>,
+    credentials: bool,
+    max_age: Option<u64>,
+}
+
+impl CorsPolicy {
+    pub fn new(route: &str, config: &Cors) -> Result<Self, Errors> {
+        let mut origins = Vec::new();
+        for origin in config.allow_origins.iter().flatten() {
+            let origin = origin.trim_end_matches('/').to_ascii_lowercase();
+            origins.push(match origin.split_once('*') {
+                _ if origin == "*" => Origin::Any,
+                Some((prefix, suffix)) if !suffix.contains('*') => {
+                    Origin::Wildcard(prefix.to_string(), suffix.to_string())
+                }
+                Some(_) => {
+                    return Err(Errors::ConfigError(format!(
+                        "Invalid CORS origin {} of route {}, only one `*` is allowed",
+                        origin, route
+                    )));
+                }
+                None => Origin::Exact(origin),
+            });
+        }
+        for pattern in config.allow_origin_regex.iter().flatten() {
+            let regex = Regex::new(pattern).map_err(|e| {
+                Errors::ConfigError(format!(
+                    "Invalid CORS origin regex {} of route {}: {}",
+                    pattern, route, e
+                ))
+            })?;
+            origins.push(Origin::Regex(regex));
+        }
+        if origins.is_empty() {
+            return Err(Errors::ConfigError(format!(
+                "CORS policy of route {} has no allowed origins",
+                route
+            )));
+        }
+        let join = |values: &Option<Vec<String>>| values.as_ref().map(|v| v.join(", "));
+        Ok(Self {
+            origins,
+            methods: match &config.allow_methods {
+                Some(methods) => methods.iter().map(|m| m.to_ascii_uppercase()).collect(),
+                None => DEFAULT_METHODS.iter().map(|m| m.to_string()).collect(),
+            },
+            headers: join(&config.allow_headers),
+            expose_headers: join(&config.expose_headers),
+            credentials: config.allow_credentials.unwrap_or(false),
+            max_age: config.max_age,
+        })
+    }
+
+    // `Access-Control-Allow-Origin` value for an allowed origin
+    pub fn allow_origin(&self, origin: &str) -> Option<String> {
+        if !self.origins.iter().any(|o| o.matches(origin)) {
+            return None;
+        }
+        // credentials can't be used with a wildcard origin
+        if !self.credentials && self.origins.iter().any(|o| matches!(o, Origin::Any)) {
+            return Some("*".to_string());
+        }
+        Some(origin.to_string())
+    }
+
+    // headers of a preflight response, `None` when the request isn't allowed
+    pub fn preflight(
+        &self,
+        origin: &str,
+        method: &str,
+        request_headers: Option<&str>,
+    ) -> Option<Vec<(&'static str, String)>> {
+        let allow_origin = self.allow_origin(origin)?;
+        if !self.methods.iter().any(|m| m.eq_ignore_ascii_case(method)) {
+            return None;
+        }
+        let mut headers = self.common(allow_origin);
+        headers.push(("Access-Control-Allow-Methods", self.methods.join(", ")));
+        match (&self.headers, request_headers) {
+            (Some(allowed), _) => headers.push(("Access-Control-Allow-Headers", allowed.clone())),
+            (None, Some(requested)) => {
+                headers.push(("Access-Control-Allow-Headers", requested.to_string()));
+                headers.push(("Vary", "Access-Control-Request-Headers".to_string()));
+            }
+            (None, None) => {}
+        }
+        if let Some(max_age) = self.max_age {
+            headers.push(("Access-Control-Max-Age", max_age.to_string()));
+        }
+        Some(headers)
+    }
+
+    // headers added to an actual response
+    pub fn response_headers(&self, origin: &str) -> Vec<(&'static str, String)> {
+        let Some(allow_origin) = self.allow_origin(origin) else {
+            return Vec::new();
+        };
+        let mut headers = self.common(allow_origin);
+        if let Some(expose) = &self.expose_headers {
+            headers.push(("Access-Control-Expose-Headers", expose.clone()));
+        }
+        headers
+    }
+
+    fn common(&self, allow_origin: String) -> Vec<(&'static str, String)> {
+        let mut headers = Vec::new();
+        if allow_origin != "*" {
+            headers.push(("Vary", "Origin".to_string()));
+        }
+        headers.push(("Access-Control-Allow-Origin", allow_origin));
+        if self.credentials {
+            headers.push(("Access-Control-Allow-Credentials", "true".to_string()));
+        }
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cors() {
+        let policy = CorsPolicy::new(
+            "test",
+            &Cors {
+                allow_origins: Some(vec![
+                    "https://app.example.com".to_string(),
+                    "https://*.example.org".to_string(),
+                ]),
+                allow_origin_regex: Some(vec![r"^http://localhost:\d+$".to_string()]),
+                allow_methods: Some(vec!["get".to_string(), "put".to_string()]),
+                allow_credentials: Some(true),
+                max_age: Some(600),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            policy.allow_origin("https://app.example.com").as_deref(),
+            Some("https://app.example.com")
+        );
+        assert!(policy.allow_origin("https://a.example.org").is_some());
+        assert!(policy.allow_origin("https://.example.org").is_none());
+        assert!(policy.allow_origin("http://localhost:3000").is_some());
+        assert!(policy.allow_origin("https://evil.com").is_none());
+
+        let headers = policy
+            .preflight("https://app.example.com", "PUT", Some("x-token"))
+            .unwrap();
+        assert!(headers.contains(&("Access-Control-Allow-Methods", "GET, PUT".to_string())));
+        assert!(headers.contains(&("Access-Control-Allow-Headers", "x-token".to_string())));
+        assert!(headers.contains(&("Access-Control-Allow-Credentials", "true".to_string())));
+        assert!(headers.contains(&("Access-Control-Max-Age", "600".to_string())));
+        assert!(policy
+            .preflight("https://app.example.com", "DELETE", None)
+            .is_none());
+
+        let any = CorsPolicy::new(
+            "test",
+            &Cors {
+                allow_origins: Some(vec!["*".to_string()]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            any.response_headers("https://evil.com"),
+            vec![("Access-Control-Allow-Origin", "*".to_string())]
+        );
+    }
+}
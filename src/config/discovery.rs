@@ -0,0 +1,515 @@
+use super::{
+    backend::build_backends,
+    proxy::{Discovery, Endpoint, Upstream},
+};
+use crate::errors::Errors;
+use async_trait::async_trait;
+use pingora::{
+    lb::{discovery::ServiceDiscovery, Backend},
+    ErrorType,
+};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+// Reads the endpoints of a service from a json or yaml file.
+// The file is only parsed again when its modification time changes,
+// so `discover` is cheap enough to be called on every background tick.
+pub struct FileDiscovery {
+    service: String,
+    path: PathBuf,
+    upstream: Option<Upstream>,
+    // modification time -> backends read at that time
+    cache: Mutex<Option<(SystemTime, BTreeSet<Backend>)>>,
+}
+
+impl FileDiscovery {
+    pub fn new(service: &str, path: &str, upstream: Option<Upstream>) -> Box<Self> {
+        Box::new(Self {
+            service: service.to_string(),
+            path: PathBuf::from(path),
+            upstream,
+            cache: Mutex::new(None),
+        })
+    }
+
+    fn read(&self) -> Result<BTreeSet<Backend>, Errors> {
+        let data = std::fs::read(&self.path).map_err(|e| {
+            Errors::ConfigError(format!(
+                "Unable to read endpoints file {:?}: {}",
+                self.path, e
+            ))
+        })?;
+        let is_json = self
+            .path
+            .extension()
+            .map(|ext| ext == "json")
+            .unwrap_or(false);
+        let endpoints: Vec<Endpoint> = if is_json {
+            serde_json::from_slice(&data).map_err(|e| e.to_string())
+        } else {
+            serde_yml::from_slice(&data).map_err(|e| e.to_string())
+        }
+        .map_err(|e| {
+            Errors::ConfigError(format!(
+                "Unable to parse endpoints file {:?}: {}",
+                self.path, e
+            ))
+        })?;
+        build_backends(&self.service, &endpoints, self.upstream.as_ref())
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for FileDiscovery {
+    async fn discover(&self) -> pingora::Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        let modified = match std::fs::metadata(&self.path).and_then(|m| m.modified()) {
+            Ok(val) => val,
+            Err(e) => {
+                return Err(pingora::Error::because(
+                    ErrorType::InternalError,
+                    "[discovery]",
+                    Errors::ConfigError(format!(
+                        "Unable to read endpoints file {:?}: {}",
+                        self.path, e
+                    )),
+                ));
+            }
+        };
+        let mut cache = match self.cache.lock() {
+            Ok(val) => val,
+            Err(e) => e.into_inner(),
+        };
+        if let Some((last_modified, backends)) = cache.as_ref() {
+            if *last_modified == modified {
+                return Ok((backends.clone(), HashMap::new()));
+            }
+        }
+        let backends = match self.read() {
+            Ok(val) => val,
+            Err(e) => {
+                return Err(pingora::Error::because(
+                    ErrorType::InternalError,
+                    "[discovery]",
+                    e,
+                ));
+            }
+        };
+        tracing::info!(
+            "Loaded {} endpoints for service {} from {:?}",
+            backends.len(),
+            self.service,
+            self.path
+        );
+        *cache = Some((modified, backends.clone()));
+        Ok((backends, HashMap::new()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CatalogFormat {
+    // json list of endpoints, same shape as the `endpoints` config
+    Generic,
+    // consul `/v1/health/service/<name>` response
+    Consul,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulEntry {
+    node: ConsulNode,
+    service: ConsulService,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulNode {
+    address: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulService {
+    #[serde(default)]
+    address: String,
+    port: u16,
+    #[serde(default)]
+    tags: Option<Vec<String>>,
+    #[serde(default)]
+    meta: Option<BTreeMap<String, String>>,
+    #[serde(default)]
+    weights: Option<ConsulWeights>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulWeights {
+    passing: u32,
+}
+
+#[derive(Default)]
+struct PollState {
+    next_poll: Option<Instant>,
+    etag: Option<String>,
+    // consul blocking query index
+    index: Option<u64>,
+    // endpoints of the last catalog, as json
+    endpoints: Option<String>,
+}
+
+// Polls a json catalog over http.
+// Generic catalogs are polled every `interval` and honor ETag / If-None-Match,
+// consul catalogs use blocking queries so changes are picked up as soon as they happen.
+pub struct HttpDiscovery {
+    service: String,
+    url: String,
+    format: CatalogFormat,
+    interval: Duration,
+    tags: Vec<String>,
+    upstream: Option<Upstream>,
+    client: reqwest::Client,
+    poll: tokio::sync::Mutex<PollState>,
+    cache: Mutex<Option<BTreeSet<Backend>>>,
+}
+
+impl HttpDiscovery {
+    pub fn new(
+        service: &str,
+        discovery: &Discovery,
+        upstream: Option<Upstream>,
+    ) -> Result<Box<Self>, Errors> {
+        let Some(url) = &discovery.url else {
+            return Err(Errors::ConfigError(format!(
+                "Http discovery of service {} requires a url",
+                service
+            )));
+        };
+        let format = match discovery.format.as_deref() {
+            None | Some("generic") => CatalogFormat::Generic,
+            Some("consul") => CatalogFormat::Consul,
+            Some(format) => {
+                return Err(Errors::ConfigError(format!(
+                    "Unknown discovery format: {}",
+                    format
+                )));
+            }
+        };
+        Ok(Box::new(Self {
+            service: service.to_string(),
+            url: url.clone(),
+            format,
+            interval: Duration::from_secs(discovery.interval.unwrap_or(10)),
+            tags: discovery.tags.clone().unwrap_or_default(),
+            upstream,
+            client: reqwest::Client::new(),
+            poll: tokio::sync::Mutex::new(PollState::default()),
+            cache: Mutex::new(None),
+        }))
+    }
+
+    fn cached(&self) -> Option<BTreeSet<Backend>> {
+        match self.cache.lock() {
+            Ok(val) => val.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    fn parse(&self, body: &[u8]) -> Result<Vec<Endpoint>, Errors> {
+        let endpoints: Vec<Endpoint> = match self.format {
+            CatalogFormat::Generic => serde_json::from_slice(body).map_err(|e| {
+                Errors::ConfigError(format!("Unable to parse catalog {}: {}", self.url, e))
+            })?,
+            CatalogFormat::Consul => {
+                let entries: Vec<ConsulEntry> = serde_json::from_slice(body).map_err(|e| {
+                    Errors::ConfigError(format!("Unable to parse catalog {}: {}", self.url, e))
+                })?;
+                entries
+                    .into_iter()
+                    .map(|entry| Endpoint {
+                        ip: Some(if entry.service.address.is_empty() {
+                            entry.node.address
+                        } else {
+                            entry.service.address
+                        }),
+                        port: Some(entry.service.port),
+                        weight: entry.service.weights.map(|w| w.passing.max(1)),
+                        tags: entry.service.tags,
+                        metadata: entry.service.meta,
+                        ..Default::default()
+                    })
+                    .collect()
+            }
+        };
+        Ok(endpoints
+            .into_iter()
+            .filter(|e| {
+                let tags = e.tags.as_deref().unwrap_or_default();
+                self.tags.iter().all(|t| tags.contains(t))
+            })
+            .collect())
+    }
+
+    // returns `None` when the endpoints have not changed since the last poll
+    async fn fetch(&self, poll: &mut PollState) -> Result<Option<BTreeSet<Backend>>, Errors> {
+        let mut request = self
+            .client
+            .get(&self.url)
+            .header("User-Agent", "easy-proxy/discovery");
+        let mut timeout = Duration::from_secs(10);
+        if self.format == CatalogFormat::Consul {
+            if let Some(index) = poll.index {
+                request = request.query(&[
+                    ("index", index.to_string()),
+                    ("wait", format!("{}s", self.interval.as_secs())),
+                ]);
+                timeout += self.interval;
+            }
+        }
+        if let Some(etag) = &poll.etag {
+            request = request.header("If-None-Match", etag.as_str());
+        }
+        let resp = request.timeout(timeout).send().await.map_err(|e| {
+            Errors::ConfigError(format!("Unable to fetch catalog {}: {}", self.url, e))
+        })?;
+        let header = |name: &str| {
+            resp.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        let etag = header("ETag");
+        let index = header("X-Consul-Index").and_then(|v| v.parse::<u64>().ok());
+        let status = resp.status();
+        if status == reqwest::StatusCode::NOT_MODIFIED {
+            poll.next_poll = Some(Instant::now() + self.interval);
+            return Ok(None);
+        }
+        if !status.is_success() {
+            return Err(Errors::ConfigError(format!(
+                "Unable to fetch catalog {}: status {}",
+                self.url, status
+            )));
+        }
+        let body = resp.bytes().await.map_err(|e| {
+            Errors::ConfigError(format!("Unable to read catalog {}: {}", self.url, e))
+        })?;
+        let endpoints = self.parse(&body)?;
+        // unchanged endpoints keep their backends, and with them the stats of the backends
+        let fingerprint = serde_json::to_string(&endpoints).ok();
+        let backends = if fingerprint.is_some() && fingerprint == poll.endpoints {
+            None
+        } else {
+            Some(build_backends(
+                &self.service,
+                &endpoints,
+                self.upstream.as_ref(),
+            )?)
+        };
+        poll.endpoints = fingerprint;
+        poll.etag = etag;
+        match index {
+            // reset the blocking query when the index goes backwards
+            Some(index) if index > 0 && index >= poll.index.unwrap_or(0) => {
+                poll.index = Some(index);
+                // the next blocking query waits for a change on the server side
+                poll.next_poll = Some(Instant::now());
+            }
+            _ => {
+                poll.index = None;
+                poll.next_poll = Some(Instant::now() + self.interval);
+            }
+        }
+        Ok(backends)
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for HttpDiscovery {
+    async fn discover(&self) -> pingora::Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        let cached = self.cached();
+        // a poll is already in flight (e.g. a blocking query), keep the current backends
+        let mut poll = match (self.poll.try_lock(), &cached) {
+            (Ok(val), _) => val,
+            (Err(_), Some(backends)) => return Ok((backends.clone(), HashMap::new())),
+            (Err(_), None) => self.poll.lock().await,
+        };
+        if let (Some(next_poll), Some(backends)) = (poll.next_poll, &cached) {
+            if Instant::now() < next_poll {
+                return Ok((backends.clone(), HashMap::new()));
+            }
+        }
+        match self.fetch(&mut poll).await {
+            Ok(Some(backends)) => {
+                let mut cache = match self.cache.lock() {
+                    Ok(val) => val,
+                    Err(e) => e.into_inner(),
+                };
+                *cache = Some(backends.clone());
+                Ok((backends, HashMap::new()))
+            }
+            Ok(None) => Ok((cached.unwrap_or_default(), HashMap::new())),
+            Err(e) => {
+                poll.index = None;
+                poll.next_poll = Some(Instant::now() + self.interval);
+                Err(pingora::Error::because(
+                    ErrorType::InternalError,
+                    "[discovery]",
+                    e,
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{backend::EndpointMetadata, selection::BackendStats};
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::{mpsc, Arc},
+    };
+
+    // serves the given raw http responses in order and reports each request line
+    fn stub_server(responses: Vec<String>) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buffer = [0; 4096];
+                let n = stream.read(&mut buffer).unwrap();
+                let request = String::from_utf8_lossy(&buffer[..n]).to_string();
+                let _ = tx.send(request.lines().next().unwrap_or_default().to_string());
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (format!("http://{}/catalog", addr), rx)
+    }
+
+    fn ok_response(headers: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            headers,
+            body.len(),
+            body
+        )
+    }
+
+    fn discovery(url: &str, format: &str, tags: Option<Vec<String>>) -> Box<HttpDiscovery> {
+        let config = Discovery {
+            discovery_type: "http".to_string(),
+            path: None,
+            url: Some(url.to_string()),
+            format: Some(format.to_string()),
+            interval: Some(0),
+            tags,
+        };
+        HttpDiscovery::new("test", &config, None).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_generic_catalog_with_etag() {
+        let body = r#"[
+            {"ip": "127.0.0.1", "port": 3000, "weight": 5, "tags": ["primary"]},
+            {"ip": "127.0.0.1", "port": 3001}
+        ]"#;
+        let (url, requests) = stub_server(vec![
+            ok_response("ETag: \"v1\"\r\n", body),
+            "HTTP/1.1 304 Not Modified\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string(),
+        ]);
+        let disco = discovery(&url, "generic", Some(vec!["primary".to_string()]));
+
+        let (backends, _) = disco.discover().await.unwrap();
+        assert_eq!(backends.len(), 1);
+        let backend = backends.iter().next().unwrap();
+        assert_eq!(backend.addr.to_string(), "127.0.0.1:3000");
+        assert_eq!(backend.weight, 5);
+
+        // unchanged catalog keeps the cached backends
+        let (backends, _) = disco.discover().await.unwrap();
+        assert_eq!(backends.len(), 1);
+        assert_eq!(requests.recv().unwrap(), "GET /catalog HTTP/1.1");
+        assert_eq!(requests.recv().unwrap(), "GET /catalog HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_catalog_keeps_stats() {
+        let body = r#"[{"ip": "127.0.0.1", "port": 3000}]"#;
+        let (url, _) = stub_server(vec![ok_response("", body), ok_response("", body)]);
+        let disco = discovery(&url, "generic", None);
+        let stats = |backends: &BTreeSet<Backend>| {
+            BackendStats::get(backends.iter().next().unwrap())
+                .unwrap()
+                .clone()
+        };
+
+        let (backends, _) = disco.discover().await.unwrap();
+        let first = stats(&backends);
+        first.start();
+        let (backends, _) = disco.discover().await.unwrap();
+        let second = stats(&backends);
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(second.in_flight(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_consul_catalog_blocking_query() {
+        let body = r#"[
+            {
+                "Node": {"Address": "127.0.0.1"},
+                "Service": {"Address": "", "Port": 8080, "Tags": null, "Weights": {"Passing": 3, "Warning": 1}}
+            },
+            {
+                "Node": {"Address": "10.0.0.1"},
+                "Service": {"Address": "127.0.0.2", "Port": 8081, "Tags": ["v2"], "Meta": {"zone": "eu-1"}}
+            }
+        ]"#;
+        let (url, requests) = stub_server(vec![
+            ok_response("X-Consul-Index: 42\r\n", body),
+            ok_response("X-Consul-Index: 43\r\n", "[]"),
+        ]);
+        let disco = discovery(&url, "consul", None);
+
+        let (backends, _) = disco.discover().await.unwrap();
+        let addrs: Vec<(String, usize)> = backends
+            .iter()
+            .map(|b| (b.addr.to_string(), b.weight))
+            .collect();
+        assert_eq!(
+            addrs,
+            vec![
+                ("127.0.0.1:8080".to_string(), 3),
+                ("127.0.0.2:8081".to_string(), 1)
+            ]
+        );
+        // tags and meta stay attached to the backends
+        let metadata: Vec<Option<&EndpointMetadata>> = backends
+            .iter()
+            .map(|b| b.ext.get::<EndpointMetadata>())
+            .collect();
+        assert_eq!(metadata[0], None);
+        assert_eq!(
+            metadata[1],
+            Some(&EndpointMetadata {
+                tags: vec!["v2".to_string()],
+                metadata: BTreeMap::from([("zone".to_string(), "eu-1".to_string())]),
+            })
+        );
+
+        let (backends, _) = disco.discover().await.unwrap();
+        assert!(backends.is_empty());
+        assert_eq!(requests.recv().unwrap(), "GET /catalog HTTP/1.1");
+        assert_eq!(
+            requests.recv().unwrap(),
+            "GET /catalog?index=42&wait=0s HTTP/1.1"
+        );
+    }
+}
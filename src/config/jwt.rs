@@ -0,0 +1,454 @@
+use super::proxy::{Jwk, Jwt as JwtConfig};
+use crate::errors::Errors;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use ring::signature::{
+    RsaPublicKeyComponents, UnparsedPublicKey, ECDSA_P256_SHA256_FIXED, RSA_PKCS1_2048_8192_SHA256,
+};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug)]
+enum KeyMaterial {
+    Hs256(Vec<u8>),
+    Rs256 { n: Vec<u8>, e: Vec<u8> },
+    // uncompressed P-256 point
+    Es256(Vec<u8>),
+}
+
+#[derive(Debug)]
+struct Key {
+    kid: Option<String>,
+    material: KeyMaterial,
+}
+
+impl Key {
+    fn alg(&self) -> &str {
+        match self.material {
+            KeyMaterial::Hs256(_) => "HS256",
+            KeyMaterial::Rs256 { .. } => "RS256",
+            KeyMaterial::Es256(_) => "ES256",
+        }
+    }
+
+    fn from_jwk(jwk: &Jwk) -> Result<Self, Errors> {
+        let field = |name: &str, value: &Option<String>| -> Result<Vec<u8>, Errors> {
+            let value = value.as_ref().ok_or_else(|| {
+                Errors::ConfigError(format!("JWK {} is missing `{}`", jwk.kty, name))
+            })?;
+            URL_SAFE_NO_PAD
+                .decode(value.trim_end_matches('='))
+                .map_err(|e| Errors::ConfigError(format!("Invalid JWK `{}`: {}", name, e)))
+        };
+        let material = match jwk.kty.as_str() {
+            "oct" => KeyMaterial::Hs256(field("k", &jwk.k)?),
+            "RSA" => KeyMaterial::Rs256 {
+                n: field("n", &jwk.n)?,
+                e: field("e", &jwk.e)?,
+            },
+            "EC" if jwk.crv.as_deref().unwrap_or("P-256") == "P-256" => {
+                let mut point = vec![0x04];
+                point.extend(field("x", &jwk.x)?);
+                point.extend(field("y", &jwk.y)?);
+                KeyMaterial::Es256(point)
+            }
+            kty => {
+                return Err(Errors::ConfigError(format!(
+                    "Unsupported JWK type: {}",
+                    kty
+                )));
+            }
+        };
+        let key = Key {
+            kid: jwk.kid.clone(),
+            material,
+        };
+        if jwk.alg.as_ref().is_some_and(|alg| alg != key.alg()) {
+            return Err(Errors::ConfigError(format!(
+                "Unsupported JWK alg {:?} for {} keys",
+                jwk.alg, jwk.kty
+            )));
+        }
+        Ok(key)
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match &self.material {
+            KeyMaterial::Hs256(secret) => Hmac::<Sha256>::new_from_slice(secret)
+                .map(|mut mac| {
+                    mac.update(message);
+                    mac.verify_slice(signature).is_ok()
+                })
+                .unwrap_or(false),
+            KeyMaterial::Rs256 { n, e } => RsaPublicKeyComponents { n, e }
+                .verify(&RSA_PKCS1_2048_8192_SHA256, message, signature)
+                .is_ok(),
+            KeyMaterial::Es256(point) => UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point)
+                .verify(message, signature)
+                .is_ok(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Header {
+    alg: String,
+    #[serde(default)]
+    kid: Option<String>,
+}
+
+// Bearer JWT validation (RS256, ES256, HS256)
+#[derive(Debug)]
+pub struct JwtValidator {
+    keys: Vec<Key>,
+    issuer: Option<String>,
+    audience: Vec<String>,
+    required_claims: Vec<String>,
+    // claims exposed as `$JWT_<claim>`
+    pub claims: Vec<String>,
+    leeway: u64,
+    pub realm: String,
+}
+
+impl JwtValidator {
+    pub fn new(route: &str, config: &JwtConfig) -> Result<Self, Errors> {
+        let mut keys = Vec::new();
+        if let Some(path) = &config.jwks_file {
+            let data = std::fs::read(path).map_err(|e| {
+                Errors::ConfigError(format!("Unable to read JWKS file {}: {}", path, e))
+            })?;
+            let jwks: JwkSet = serde_json::from_slice(&data).map_err(|e| {
+                Errors::ConfigError(format!("Unable to parse JWKS file {}: {}", path, e))
+            })?;
+            for jwk in jwks.keys.iter() {
+                keys.push(Key::from_jwk(jwk)?);
+            }
+        }
+        for jwk in config.keys.iter().flatten() {
+            keys.push(Key::from_jwk(jwk)?);
+        }
+        if let Some(secret) = &config.secret {
+            keys.push(Key {
+                kid: None,
+                material: KeyMaterial::Hs256(secret.as_bytes().to_vec()),
+            });
+        }
+        if keys.is_empty() {
+            return Err(Errors::ConfigError(format!(
+                "JWT validation of route {} has no keys",
+                route
+            )));
+        }
+        Ok(Self {
+            keys,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone().unwrap_or_default(),
+            required_claims: config.required_claims.clone().unwrap_or_default(),
+            claims: config.claims.clone().unwrap_or_default(),
+            leeway: config.leeway.unwrap_or(0),
+            realm: config.realm.clone().unwrap_or_else(|| route.to_string()),
+        })
+    }
+
+    // `WWW-Authenticate` value, with the error when a token was sent
+    pub fn challenge(&self, error: Option<&str>) -> String {
+        let realm = self.realm.replace('"', "'");
+        match error {
+            Some(e) => format!(
+                "Bearer realm=\"{}\", error=\"invalid_token\", error_description=\"{}\"",
+                realm,
+                e.replace('"', "'")
+            ),
+            None => format!("Bearer realm=\"{}\"", realm),
+        }
+    }
+
+    // claims of a valid token
+    pub fn validate(&self, token: &str) -> Result<Map<String, Value>, String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        self.validate_at(token, now)
+    }
+
+    fn validate_at(&self, token: &str, now: u64) -> Result<Map<String, Value>, String> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err("malformed token".to_string());
+        };
+        let decode = |part: &str| {
+            URL_SAFE_NO_PAD
+                .decode(part)
+                .map_err(|_| "malformed token".to_string())
+        };
+        let header: Header =
+            serde_json::from_slice(&decode(header)?).map_err(|_| "malformed header")?;
+        let message = &token[..token.len() - signature.len() - 1];
+        let signature = decode(signature)?;
+        let verified = self
+            .keys
+            .iter()
+            .filter(|k| k.alg() == header.alg)
+            .filter(|k| match (&k.kid, &header.kid) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            })
+            .any(|k| k.verify(message.as_bytes(), &signature));
+        if !verified {
+            return Err("invalid signature".to_string());
+        }
+
+        let claims: Map<String, Value> =
+            serde_json::from_slice(&decode(payload)?).map_err(|_| "malformed claims")?;
+        // NumericDate may be fractional, a time claim that isn't a number invalidates the token
+        let time = |name: &str| match claims.get(name) {
+            Some(value) => value
+                .as_f64()
+                .map(Some)
+                .ok_or_else(|| format!("invalid claim {}", name)),
+            None => Ok(None),
+        };
+        // claims are untrusted input, compared as floats the additions can't overflow
+        let (now, leeway) = (now as f64, self.leeway as f64);
+        if time("exp")?.is_some_and(|exp| now > exp + leeway) {
+            return Err("token expired".to_string());
+        }
+        if time("nbf")?.is_some_and(|nbf| now + leeway < nbf) {
+            return Err("token not yet valid".to_string());
+        }
+        if let Some(issuer) = &self.issuer {
+            if claims.get("iss").and_then(|v| v.as_str()) != Some(issuer) {
+                return Err("invalid issuer".to_string());
+            }
+        }
+        if !self.audience.is_empty() {
+            let aud: Vec<&str> = match claims.get("aud") {
+                Some(Value::String(aud)) => vec![aud.as_str()],
+                Some(Value::Array(aud)) => aud.iter().filter_map(|v| v.as_str()).collect(),
+                _ => Vec::new(),
+            };
+            if !aud.iter().any(|a| self.audience.iter().any(|b| a == b)) {
+                return Err("invalid audience".to_string());
+            }
+        }
+        if let Some(missing) = self
+            .required_claims
+            .iter()
+            .find(|c| !claims.contains_key(c.as_str()))
+        {
+            return Err(format!("missing claim {}", missing));
+        }
+        Ok(claims)
+    }
+}
+
+// token of a `Bearer` `Authorization` header, the scheme is case-insensitive
+pub fn bearer_token(authorization: &str) -> Option<&str> {
+    let (scheme, token) = authorization.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    Some(token.trim())
+}
+
+// variable value of a claim, strings as they are, everything else as json
+pub fn claim_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+    };
+    use serde_json::json;
+
+    fn token(header: Value, claims: Value, sign: impl Fn(&[u8]) -> Vec<u8>) -> String {
+        let message = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signature = sign(message.as_bytes());
+        format!("{}.{}", message, URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    #[test]
+    fn test_hs256() {
+        let validator = JwtValidator::new(
+            "test",
+            &JwtConfig {
+                secret: Some("secret".to_string()),
+                issuer: Some("easy-proxy".to_string()),
+                audience: Some(vec!["api".to_string()]),
+                required_claims: Some(vec!["sub".to_string()]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let sign = |secret: &'static str| {
+            move |message: &[u8]| {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+                mac.update(message);
+                mac.finalize().into_bytes().to_vec()
+            }
+        };
+        let header = json!({"alg": "HS256", "typ": "JWT"});
+        let claims = json!({"sub": "42", "iss": "easy-proxy", "aud": ["api"], "exp": 2000});
+
+        let valid = token(header.clone(), claims.clone(), sign("secret"));
+        let result = validator.validate_at(&valid, 1000).unwrap();
+        assert_eq!(claim_value(&result["sub"]), "42");
+        assert_eq!(
+            validator.validate_at(&valid, 3000),
+            Err("token expired".to_string())
+        );
+        let forged = token(header.clone(), claims, sign("other"));
+        assert_eq!(
+            validator.validate_at(&forged, 1000),
+            Err("invalid signature".to_string())
+        );
+        let no_sub = token(
+            header,
+            json!({"iss": "easy-proxy", "aud": "api"}),
+            sign("secret"),
+        );
+        assert_eq!(
+            validator.validate_at(&no_sub, 1000),
+            Err("missing claim sub".to_string())
+        );
+        // alg none is never accepted
+        let none = token(json!({"alg": "none"}), json!({"sub": "42"}), |_| Vec::new());
+        assert!(validator.validate_at(&none, 1000).is_err());
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        assert_eq!(bearer_token("Bearer abc"), Some("abc"));
+        assert_eq!(bearer_token("bearer abc"), Some("abc"));
+        assert_eq!(bearer_token("BEARER  abc "), Some("abc"));
+        assert_eq!(bearer_token("Basic abc"), None);
+        assert_eq!(bearer_token("Bearer"), None);
+    }
+
+    #[test]
+    fn test_time_claims_overflow() {
+        let validator = JwtValidator::new(
+            "test",
+            &JwtConfig {
+                secret: Some("secret".to_string()),
+                leeway: Some(60),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let sign = |message: &[u8]| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+            mac.update(message);
+            mac.finalize().into_bytes().to_vec()
+        };
+        let header = json!({"alg": "HS256"});
+        let far = token(header.clone(), json!({"exp": u64::MAX}), sign);
+        assert!(validator.validate_at(&far, 1000).is_ok());
+        let not_yet = token(header, json!({"nbf": u64::MAX}), sign);
+        assert_eq!(
+            validator.validate_at(&not_yet, 1000),
+            Err("token not yet valid".to_string())
+        );
+        assert!(validator.validate_at(&not_yet, u64::MAX - 1).is_ok());
+    }
+
+    #[test]
+    fn test_numeric_dates() {
+        let validator = JwtValidator::new(
+            "test",
+            &JwtConfig {
+                secret: Some("secret".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let sign = |message: &[u8]| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+            mac.update(message);
+            mac.finalize().into_bytes().to_vec()
+        };
+        let header = json!({"alg": "HS256"});
+        let fractional = token(header.clone(), json!({"exp": 1.7e9, "nbf": 999.5}), sign);
+        assert!(validator.validate_at(&fractional, 1000).is_ok());
+        assert_eq!(
+            validator.validate_at(&fractional, 1_700_000_001),
+            Err("token expired".to_string())
+        );
+        let negative = token(header.clone(), json!({"exp": -1}), sign);
+        assert_eq!(
+            validator.validate_at(&negative, 1000),
+            Err("token expired".to_string())
+        );
+        // a malformed exp never makes the token valid forever
+        for exp in [json!("2000"), json!(true), json!(null), json!([2000])] {
+            let malformed = token(header.clone(), json!({ "exp": exp }), sign);
+            assert_eq!(
+                validator.validate_at(&malformed, 1000),
+                Err("invalid claim exp".to_string())
+            );
+        }
+        let malformed = token(header, json!({"nbf": "1000"}), sign);
+        assert_eq!(
+            validator.validate_at(&malformed, 1000),
+            Err("invalid claim nbf".to_string())
+        );
+    }
+
+    #[test]
+    fn test_es256() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+                .unwrap();
+        let public = key_pair.public_key().as_ref();
+        let validator = JwtValidator::new(
+            "test",
+            &JwtConfig {
+                keys: Some(vec![Jwk {
+                    kty: "EC".to_string(),
+                    kid: Some("k1".to_string()),
+                    crv: Some("P-256".to_string()),
+                    x: Some(URL_SAFE_NO_PAD.encode(&public[1..33])),
+                    y: Some(URL_SAFE_NO_PAD.encode(&public[33..65])),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let valid = token(
+            json!({"alg": "ES256", "kid": "k1"}),
+            json!({"sub": "42"}),
+            |message| key_pair.sign(&rng, message).unwrap().as_ref().to_vec(),
+        );
+        assert!(validator.validate_at(&valid, 1000).is_ok());
+        let other_kid = token(
+            json!({"alg": "ES256", "kid": "k2"}),
+            json!({"sub": "42"}),
+            |message| key_pair.sign(&rng, message).unwrap().as_ref().to_vec(),
+        );
+        assert!(validator.validate_at(&other_kid, 1000).is_err());
+    }
+}
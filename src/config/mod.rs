@@ -1,5 +1,15 @@
+pub mod access;
 pub mod backend;
+pub mod basic_auth;
+pub mod cache;
 pub mod certs;
+pub mod circuit_breaker;
+pub mod cors;
+pub mod discovery;
+pub mod jwt;
 pub mod proxy;
+pub mod rate_limit;
 pub mod runtime;
+pub mod selection;
+pub mod static_files;
 pub mod store;
@@ -1,6 +1,6 @@
 use crate::{config::runtime, errors::Errors};
 use serde::{Deserialize, Serialize};
-use std::{fs::File, io::BufReader, path::PathBuf};
+use std::{collections::BTreeMap, fs::File, io::BufReader, path::PathBuf};
 
 use super::store;
 
@@ -10,6 +10,31 @@ pub struct ProxyConfig {
     pub routes: Option<Vec<Route>>,
     pub services: Option<Vec<Service>>,
     pub tls: Option<Vec<Tls>>,
+    // global access control, applied before the access control of the routes
+    #[serde(default)]
+    pub access: Option<Access>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Access {
+    // allow_first, deny_first (default: deny_first)
+    #[serde(default)]
+    pub order: Option<String>,
+    // IPs or CIDRs
+    #[serde(default)]
+    pub allow: Option<Vec<String>>,
+    #[serde(default)]
+    pub deny: Option<Vec<String>>,
+    // files with one IP or CIDR per line, reloaded when they change
+    #[serde(default)]
+    pub allow_file: Option<String>,
+    #[serde(default)]
+    pub deny_file: Option<String>,
+    // response when denied (default: 403)
+    #[serde(default)]
+    pub status: Option<u16>,
+    #[serde(default)]
+    pub message: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -50,18 +75,237 @@ impl std::fmt::Display for AcmeProvider {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Service {
     pub name: String,
+    // http, static
     #[serde(rename = "type")]
     pub service_type: String,
+    #[serde(default)]
     pub algorithm: String,
+    #[serde(default)]
     pub endpoints: Vec<Endpoint>,
+    #[serde(default)]
+    pub discovery: Option<Discovery>,
+    // selection key expression, e.g. `$HEADER_x-user-id` or `$COOKIE_session`
+    #[serde(default)]
+    pub hash_key: Option<String>,
+    #[serde(default)]
+    pub sticky: Option<Sticky>,
+    #[serde(default)]
+    pub retry: Option<Retry>,
+    // connection options for every endpoint of the service
+    #[serde(default)]
+    pub upstream: Option<Upstream>,
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+    // seconds during which a new or recovered endpoint ramps up to its weight
+    #[serde(default)]
+    pub slow_start: Option<u64>,
+    #[serde(default)]
+    pub circuit_breaker: Option<CircuitBreaker>,
+    // service that receives the traffic when no primary or backup endpoint is available
+    #[serde(default)]
+    pub fallback_service: Option<String>,
+    // files of a `static` service
+    #[serde(default, rename = "static")]
+    pub static_files: Option<StaticFiles>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct StaticFiles {
+    pub root: String,
+    // files served for a directory (default: index.html)
+    #[serde(default)]
+    pub index: Option<Vec<String>>,
+    // serve the index of the root for unknown paths (default: false)
+    #[serde(default)]
+    pub spa: Option<bool>,
+    // serve `.br` / `.gz` variants next to the files to clients that accept them (default: false)
+    #[serde(default)]
+    pub precompressed: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HealthCheck {
+    // tcp, http
+    #[serde(rename = "type")]
+    pub check_type: String,
+    // request path for `http` checks (default: /)
+    #[serde(default)]
+    pub path: Option<String>,
+    // host header for `http` checks (default: localhost)
+    #[serde(default)]
+    pub host: Option<String>,
+    // seconds between checks (default: 5)
+    #[serde(default)]
+    pub interval: Option<u64>,
+    #[serde(default)]
+    pub consecutive_success: Option<usize>,
+    #[serde(default)]
+    pub consecutive_failure: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Upstream {
+    #[serde(default)]
+    pub connect_timeout_ms: Option<u64>,
+    // connect timeout including the tls handshake
+    #[serde(default)]
+    pub total_connection_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub read_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub write_timeout_ms: Option<u64>,
+    // how long an idle keepalive connection is kept in the pool
+    #[serde(default)]
+    pub idle_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub tcp_keepalive: Option<TcpKeepalive>,
+    #[serde(default)]
+    pub tcp_fast_open: Option<bool>,
+    // max in-flight requests per endpoint
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    // send a PROXY protocol v2 header on new connections, disables keepalive
+    #[serde(default)]
+    pub proxy_protocol: Option<bool>,
+}
+
+impl Upstream {
+    // the options of `other` take precedence
+    pub fn merge(&self, other: &Upstream) -> Upstream {
+        Upstream {
+            connect_timeout_ms: other.connect_timeout_ms.or(self.connect_timeout_ms),
+            total_connection_timeout_ms: other
+                .total_connection_timeout_ms
+                .or(self.total_connection_timeout_ms),
+            read_timeout_ms: other.read_timeout_ms.or(self.read_timeout_ms),
+            write_timeout_ms: other.write_timeout_ms.or(self.write_timeout_ms),
+            idle_timeout_ms: other.idle_timeout_ms.or(self.idle_timeout_ms),
+            tcp_keepalive: other.tcp_keepalive.clone().or(self.tcp_keepalive.clone()),
+            tcp_fast_open: other.tcp_fast_open.or(self.tcp_fast_open),
+            max_connections: other.max_connections.or(self.max_connections),
+            proxy_protocol: other.proxy_protocol.or(self.proxy_protocol),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TcpKeepalive {
+    pub idle_ms: u64,
+    pub interval_ms: u64,
+    pub count: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Retry {
+    // total attempts including the first one
+    pub attempts: u32,
+    // connect_error, timeout, error (default: connect_error)
+    #[serde(default)]
+    pub on: Option<Vec<String>>,
+    // upstream statuses to retry, e.g. 502, 503
+    #[serde(default)]
+    pub statuses: Option<Vec<u16>>,
+    // default: GET, HEAD, OPTIONS, PUT, DELETE, TRACE
+    #[serde(default)]
+    pub methods: Option<Vec<String>>,
+    // bounds connecting and each read and write of an attempt
+    #[serde(default)]
+    pub per_try_timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct CircuitBreaker {
+    // open after this many failures in a row
+    #[serde(default)]
+    pub consecutive_failures: Option<u32>,
+    // open when the share of failures in the window reaches this ratio, e.g. 0.5
+    #[serde(default)]
+    pub error_rate: Option<f64>,
+    // requests in the window before the error rate is evaluated (default: 20)
+    #[serde(default)]
+    pub min_requests: Option<u32>,
+    // rolling window in seconds (default: 10)
+    #[serde(default)]
+    pub window: Option<u64>,
+    // seconds before an open breaker lets trial requests through (default: 30)
+    #[serde(default)]
+    pub open_duration: Option<u64>,
+    // trial requests in the half-open state (default: 1)
+    #[serde(default)]
+    pub half_open_requests: Option<u32>,
+    // max concurrent requests to the service
+    #[serde(default)]
+    pub max_concurrent: Option<usize>,
+    // upstream statuses that count as failures (default: 500-599)
+    #[serde(default)]
+    pub statuses: Option<Vec<u16>>,
+    // response while open (default: 503)
+    #[serde(default)]
+    pub status: Option<u16>,
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Sticky {
+    // cookie name (default: easy-proxy-sticky)
+    #[serde(default)]
+    pub cookie: Option<String>,
+    // key used to sign the cookie
+    pub secret: String,
+    // cookie max-age in seconds (default: session cookie)
+    #[serde(default)]
+    pub ttl: Option<u64>,
+    #[serde(default)]
+    pub path: Option<String>,
+    // Strict, Lax, None (default: Lax)
+    #[serde(default)]
+    pub same_site: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Discovery {
+    #[serde(rename = "type")]
+    pub discovery_type: String,
+    // endpoints file for `file` discovery (json or yaml)
+    #[serde(default)]
+    pub path: Option<String>,
+    // catalog url for `http` discovery
+    #[serde(default)]
+    pub url: Option<String>,
+    // catalog format for `http` discovery: generic, consul (default: generic)
+    #[serde(default)]
+    pub format: Option<String>,
+    // seconds between catalog polls (default: 10)
+    #[serde(default)]
+    pub interval: Option<u64>,
+    // only use catalog entries that carry all of these tags
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
 pub struct Endpoint {
-    pub ip: String,
-    pub port: u16,
+    #[serde(default)]
+    pub ip: Option<String>,
+    #[serde(default)]
+    pub port: Option<u16>,
+    // unix domain socket path, used instead of `ip` and `port`
+    #[serde(default)]
+    pub unix: Option<String>,
     #[serde(default)]
     pub weight: Option<u32>,
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+    // free-form key/value pairs, e.g. the consul service meta
+    #[serde(default)]
+    pub metadata: Option<BTreeMap<String, String>>,
+    // overrides the connection options of the service
+    #[serde(default)]
+    pub upstream: Option<Upstream>,
+    // only receives traffic when no primary endpoint is available
+    #[serde(default)]
+    pub backup: Option<bool>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -75,6 +319,237 @@ pub struct Route {
     pub add_headers: Option<Vec<Header>>,
     #[serde(default)]
     pub paths: Option<Vec<Path>>,
+    // answer every path of the route without an upstream, a path's own action takes precedence
+    #[serde(default, rename = "return")]
+    pub return_response: Option<Return>,
+    #[serde(default)]
+    pub redirect: Option<Redirect>,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    pub access: Option<Access>,
+    #[serde(default)]
+    pub forwarded_headers: Option<ForwardedHeaders>,
+    #[serde(default)]
+    pub basic_auth: Option<BasicAuth>,
+    #[serde(default)]
+    pub jwt: Option<Jwt>,
+    #[serde(default)]
+    pub forward_auth: Option<ForwardAuth>,
+    #[serde(default)]
+    pub cors: Option<Cors>,
+    #[serde(default)]
+    pub response_add_headers: Option<Vec<Header>>,
+    #[serde(default)]
+    pub response_set_headers: Option<Vec<Header>>,
+    #[serde(default)]
+    pub response_remove_headers: Option<Vec<String>>,
+    #[serde(default)]
+    pub security_headers: Option<SecurityHeaders>,
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    #[serde(default)]
+    pub cache: Option<Cache>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct BasicAuth {
+    // default: the route name
+    #[serde(default)]
+    pub realm: Option<String>,
+    // htpasswd file with bcrypt or {SHA} entries
+    #[serde(default)]
+    pub htpasswd: Option<String>,
+    // inline `user:hash` entries
+    #[serde(default)]
+    pub users: Option<Vec<String>>,
+    // remove the `Authorization` header before proxying (default: false)
+    #[serde(default)]
+    pub strip_authorization: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Cache {
+    // seconds, overrides the freshness of Cache-Control / Expires (default: from the upstream)
+    #[serde(default)]
+    pub ttl: Option<u64>,
+    // template of the key, see `proxy::variables::expand` (default: `config::cache::default_key`)
+    #[serde(default)]
+    pub key: Option<String>,
+    // seconds a stale entry is served while it is refreshed (default: 0)
+    #[serde(default)]
+    pub stale_while_revalidate: Option<u32>,
+    // seconds a stale entry is served when the upstream fails (default: 0)
+    #[serde(default)]
+    pub stale_if_error: Option<u32>,
+    // concurrent misses of a key wait for the first one (default: true)
+    #[serde(default)]
+    pub coalesce: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Compression {
+    // gzip, br, zstd (default: all), picked from `Accept-Encoding`
+    #[serde(default)]
+    pub algorithms: Option<Vec<String>>,
+    // default: 6
+    #[serde(default)]
+    pub level: Option<u32>,
+    // responses with a smaller Content-Length are sent as they are (default: 1024)
+    #[serde(default)]
+    pub min_size: Option<usize>,
+    // exact types or `type/*` (default: text/*, json, javascript, xml, wasm, svg)
+    #[serde(default)]
+    pub content_types: Option<Vec<String>>,
+    // decode upstream encodings the client doesn't accept (default: true)
+    #[serde(default)]
+    pub decompress: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SecurityHeaders {
+    // Strict-Transport-Security, tls only (default: max-age=31536000; includeSubDomains)
+    #[serde(default)]
+    pub hsts: Option<String>,
+    // X-Content-Type-Options: nosniff (default: true)
+    #[serde(default)]
+    pub content_type_options: Option<bool>,
+    // X-Frame-Options (default: DENY)
+    #[serde(default)]
+    pub frame_options: Option<String>,
+    // Referrer-Policy (default: strict-origin-when-cross-origin)
+    #[serde(default)]
+    pub referrer_policy: Option<String>,
+    // Content-Security-Policy (default: none)
+    #[serde(default)]
+    pub content_security_policy: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Cors {
+    // exact origins, `*` for any or one wildcard, e.g. `https://*.example.com`
+    #[serde(default)]
+    pub allow_origins: Option<Vec<String>>,
+    #[serde(default)]
+    pub allow_origin_regex: Option<Vec<String>>,
+    // default: GET, HEAD, POST
+    #[serde(default)]
+    pub allow_methods: Option<Vec<String>>,
+    // default: the requested headers
+    #[serde(default)]
+    pub allow_headers: Option<Vec<String>>,
+    #[serde(default)]
+    pub expose_headers: Option<Vec<String>>,
+    // default: false
+    #[serde(default)]
+    pub allow_credentials: Option<bool>,
+    // seconds a preflight response may be cached
+    #[serde(default)]
+    pub max_age: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ForwardAuth {
+    // http service that authorizes the requests
+    pub service: String,
+    // default: /
+    #[serde(default)]
+    pub path: Option<String>,
+    // request headers sent to the auth service (default: authorization, cookie)
+    #[serde(default)]
+    pub request_headers: Option<Vec<String>>,
+    // auth response headers copied onto the upstream request
+    #[serde(default)]
+    pub copy_headers: Option<Vec<String>>,
+    // default: 5000
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Jwt {
+    // default: the route name
+    #[serde(default)]
+    pub realm: Option<String>,
+    // JSON Web Key Set file
+    #[serde(default)]
+    pub jwks_file: Option<String>,
+    // inline JSON Web Keys
+    #[serde(default)]
+    pub keys: Option<Vec<Jwk>>,
+    // HS256 shared secret
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub issuer: Option<String>,
+    // any of these audiences is accepted
+    #[serde(default)]
+    pub audience: Option<Vec<String>>,
+    #[serde(default)]
+    pub required_claims: Option<Vec<String>>,
+    // claims exposed as `$JWT_<claim>` variables
+    #[serde(default)]
+    pub claims: Option<Vec<String>>,
+    // allowed clock skew in seconds for exp / nbf (default: 0)
+    #[serde(default)]
+    pub leeway: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Jwk {
+    // RSA, EC, oct
+    pub kty: String,
+    #[serde(default)]
+    pub kid: Option<String>,
+    #[serde(default)]
+    pub alg: Option<String>,
+    // oct
+    #[serde(default)]
+    pub k: Option<String>,
+    // RSA
+    #[serde(default)]
+    pub n: Option<String>,
+    #[serde(default)]
+    pub e: Option<String>,
+    // EC
+    #[serde(default)]
+    pub crv: Option<String>,
+    #[serde(default)]
+    pub x: Option<String>,
+    #[serde(default)]
+    pub y: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ForwardedHeaders {
+    // append, replace, off (default: append)
+    #[serde(default)]
+    pub mode: Option<String>,
+    // RFC 7239 `Forwarded`
+    #[serde(default)]
+    pub forwarded: Option<bool>,
+    #[serde(default)]
+    pub via: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RateLimit {
+    // requests per window
+    pub requests: u32,
+    // seconds (default: 1)
+    #[serde(default)]
+    pub window: Option<u64>,
+    // extra requests allowed in a window that follows one within the limit
+    #[serde(default)]
+    pub burst: Option<u32>,
+    // template of the limit key, e.g. `$CLIENT_IP:$PATH` or `$HEADER_x-api-key` (default: $CLIENT_IP)
+    #[serde(default)]
+    pub key: Option<String>,
+    // response when over the limit (default: 429)
+    #[serde(default)]
+    pub status: Option<u16>,
+    #[serde(default)]
+    pub message: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -102,10 +577,43 @@ pub struct Path {
     #[serde(rename = "pathType")]
     pub path_type: String,
     pub path: String,
+    // not needed when the path returns a response or redirects
+    #[serde(default)]
     pub service: ServiceReference,
+    #[serde(default, rename = "return")]
+    pub return_response: Option<Return>,
+    #[serde(default)]
+    pub redirect: Option<Redirect>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// response sent by the proxy, values support variables
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Return {
+    // default: 200
+    #[serde(default)]
+    pub status: Option<u16>,
+    #[serde(default)]
+    pub headers: Option<Vec<Header>>,
+    #[serde(default)]
+    pub body: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Redirect {
+    // target, supports variables, e.g. `https://new.example.com`
+    pub to: String,
+    // 301, 302, 303, 307, 308 (default: 302)
+    #[serde(default)]
+    pub status: Option<u16>,
+    // append the request path to the target (default: false)
+    #[serde(default)]
+    pub preserve_path: Option<bool>,
+    // append the request query to the target (default: false)
+    #[serde(default)]
+    pub preserve_query: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct ServiceReference {
     pub name: String,
     #[serde(default)]
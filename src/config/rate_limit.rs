@@ -0,0 +1,170 @@
+use super::proxy::RateLimit;
+use crate::errors::Errors;
+use pingora_limits::rate::Rate;
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+// Per route sliding window rate limiter on top of the pingora count-min sketch estimator.
+// The requests of the current window are added to those of the previous window, weighted by
+// how much of the previous window still overlaps the sliding one. `requests` are allowed per
+// window, plus `burst` while the previous window stayed within the limit.
+pub struct RateLimiter {
+    rate: Rate,
+    started: Instant,
+    window: Duration,
+    requests: isize,
+    burst: isize,
+    // template of the key, see `proxy::variables::expand`
+    pub key: String,
+    pub status: u16,
+    pub message: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    // requests allowed in the sliding window, including the burst when it applies
+    pub limit: isize,
+    pub remaining: isize,
+    // seconds until the current window ends
+    pub reset: u64,
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("window", &self.window)
+            .field("requests", &self.requests)
+            .field("burst", &self.burst)
+            .field("key", &self.key)
+            .finish()
+    }
+}
+
+impl RateLimiter {
+    pub fn new(route: &str, config: &RateLimit) -> Result<Self, Errors> {
+        if config.requests == 0 {
+            return Err(Errors::ConfigError(format!(
+                "Rate limit of route {} requires requests greater than 0",
+                route
+            )));
+        }
+        let window = Duration::from_secs(config.window.unwrap_or(1).max(1));
+        Ok(Self {
+            rate: Rate::new(window),
+            started: Instant::now(),
+            window,
+            requests: config.requests as isize,
+            burst: config.burst.unwrap_or(0) as isize,
+            key: config
+                .key
+                .clone()
+                .unwrap_or_else(|| "$CLIENT_IP".to_string()),
+            status: config.status.unwrap_or(429),
+            message: config
+                .message
+                .clone()
+                .unwrap_or_else(|| "Too many requests".to_string()),
+        })
+    }
+
+    pub fn check(&self, key: &str) -> Decision {
+        let current = self.rate.observe(&key, 1);
+        let previous = self.rate.rate(&key) * self.window.as_secs_f64();
+        let window = self.window.as_secs_f64();
+        let elapsed = self.started.elapsed().as_secs_f64() % window;
+        self.decide(current, previous, elapsed)
+    }
+
+    // `current` and `previous` requests of the key, `elapsed` seconds of the current window
+    fn decide(&self, current: isize, previous: f64, elapsed: f64) -> Decision {
+        let window = self.window.as_secs_f64();
+        let estimate = previous * (1.0 - elapsed / window) + current as f64;
+        let limit = if previous.round() as isize <= self.requests {
+            self.requests + self.burst
+        } else {
+            self.requests
+        };
+        Decision {
+            allowed: estimate <= limit as f64,
+            limit,
+            remaining: (limit as f64 - estimate).floor().max(0.0) as isize,
+            reset: (window - elapsed).ceil().max(1.0) as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        let limiter = RateLimiter::new(
+            "test",
+            &RateLimit {
+                requests: 2,
+                window: Some(60),
+                burst: Some(1),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        for remaining in [2, 1, 0] {
+            let decision = limiter.check("10.0.0.1");
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+        let decision = limiter.check("10.0.0.1");
+        assert!(!decision.allowed);
+        assert_eq!(decision.limit, 3);
+        assert!(decision.reset > 0 && decision.reset <= 60);
+        // keys are limited separately
+        assert!(limiter.check("10.0.0.2").allowed);
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let limiter = RateLimiter::new(
+            "test",
+            &RateLimit {
+                requests: 10,
+                window: Some(60),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        // a full previous window blocks the start of the next one
+        let decision = limiter.decide(1, 10.0, 0.0);
+        assert!(!decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        assert_eq!(decision.reset, 60);
+        // and counts for half of it halfway through
+        assert_eq!(
+            limiter.decide(5, 10.0, 30.0),
+            Decision {
+                allowed: true,
+                limit: 10,
+                remaining: 0,
+                reset: 30,
+            }
+        );
+        assert!(!limiter.decide(6, 10.0, 30.0).allowed);
+        assert_eq!(limiter.decide(3, 10.0, 45.0).remaining, 4);
+        // a previous window over the limit takes the burst away
+        let limiter = RateLimiter::new(
+            "test",
+            &RateLimit {
+                requests: 10,
+                window: Some(60),
+                burst: Some(5),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(limiter.decide(1, 0.0, 0.0).limit, 15);
+        assert_eq!(limiter.decide(1, 12.0, 0.0).limit, 10);
+    }
+}
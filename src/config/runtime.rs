@@ -1,3 +1,4 @@
+use super::access::Cidr;
 use crate::errors::Errors;
 use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
@@ -9,12 +10,41 @@ pub struct RuntimeConfig {
     pub pingora: Pingora,
     pub config_dir: String,
     pub acme_store: Option<String>,
+    // peers allowed to set the client IP through forwarding headers
+    #[serde(default)]
+    pub trusted_proxies: Option<Vec<String>>,
+    // value of the `x-server` response header, empty to drop it (default: Easy Proxy)
+    #[serde(default)]
+    pub server_header: Option<String>,
+    #[serde(default)]
+    pub cache: Option<CacheStorage>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CacheStorage {
+    // bytes kept in memory before the least recently used entries are evicted (default: 128 MiB)
+    #[serde(default)]
+    pub max_size: Option<usize>,
+    // how long concurrent misses wait for the first one (default: 2000)
+    #[serde(default)]
+    pub lock_timeout_ms: Option<u64>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Proxy {
     pub http: String,
     pub https: Option<String>,
+    #[serde(default)]
+    pub proxy_protocol: Option<ProxyProtocol>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ProxyProtocol {
+    // http, https (default: both)
+    #[serde(default)]
+    pub listeners: Option<Vec<String>>,
+    // sources allowed to send PROXY protocol headers
+    pub trusted: Vec<String>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -35,6 +65,7 @@ pub struct Pingora {
 
 // Initialize global configuration
 static GLOBAL_RUNTIME_CONFIG: OnceCell<RuntimeConfig> = OnceCell::new();
+static TRUSTED_PROXIES: OnceCell<Vec<Cidr>> = OnceCell::new();
 
 pub fn initialize() -> Result<(), Errors> {
     let conf_path = if let Ok(val) = env::var("EASY_PROXY_CONF") {
@@ -57,11 +88,28 @@ pub fn initialize() -> Result<(), Errors> {
         ))
     })?;
 
+    let trusted_proxies = config
+        .trusted_proxies
+        .iter()
+        .flatten()
+        .map(|c| c.parse())
+        .collect::<Result<Vec<Cidr>, Errors>>()?;
+    TRUSTED_PROXIES
+        .set(trusted_proxies)
+        .map_err(|_| Errors::ConfigError("Global config has already been set".to_string()))?;
+
     GLOBAL_RUNTIME_CONFIG
         .set(config)
         .map_err(|_| Errors::ConfigError("Global config has already been set".to_string()))
 }
 
+pub fn trusted_proxies() -> &'static [Cidr] {
+    TRUSTED_PROXIES
+        .get()
+        .map(|v| v.as_slice())
+        .unwrap_or_default()
+}
+
 pub fn config() -> &'static RuntimeConfig {
     GLOBAL_RUNTIME_CONFIG.get().expect("Config not initialized")
 }
@@ -0,0 +1,424 @@
+use pingora::lb::{
+    selection::{BackendIter, BackendSelection},
+    Backend,
+};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, LazyLock,
+    },
+    time::{Duration, Instant},
+};
+
+// decay time of the latency ewma
+static EWMA_DECAY: Duration = Duration::from_secs(10);
+static STATS_EPOCH: LazyLock<Instant> = LazyLock::new(Instant::now);
+// share of the weight a backend starts with in slow start
+static SLOW_START_MIN: f64 = 0.1;
+// points on the consistent hashing ring per unit of weight, as in ketama
+static RING_POINTS: usize = 160;
+
+// Per backend load statistics.
+// Stored in `Backend::ext` behind an `Arc`, so every clone of a backend shares the same counters.
+#[derive(Debug, Default)]
+pub struct BackendStats {
+    in_flight: AtomicUsize,
+    // 0 means unlimited
+    max_in_flight: usize,
+    // peak ewma of the upstream latency in microseconds (f64 bits)
+    ewma: AtomicU64,
+    // last ewma update in microseconds since `STATS_EPOCH`
+    updated: AtomicU64,
+    // start of the slow start in microseconds since `STATS_EPOCH`, 0 when warmed up
+    slow_start: AtomicU64,
+    // health seen by the last health check
+    unhealthy: AtomicBool,
+}
+
+impl BackendStats {
+    pub fn with_max_in_flight(max_in_flight: usize) -> Self {
+        Self {
+            max_in_flight,
+            ..Default::default()
+        }
+    }
+
+    pub fn get(backend: &Backend) -> Option<&Arc<BackendStats>> {
+        backend.ext.get::<Arc<BackendStats>>()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn has_capacity(&self) -> bool {
+        self.max_in_flight == 0 || self.in_flight() < self.max_in_flight
+    }
+
+    pub fn start(&self) {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn finish(&self) {
+        let _ = self
+            .in_flight
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| v.checked_sub(1));
+    }
+
+    pub fn ewma(&self) -> f64 {
+        f64::from_bits(self.ewma.load(Ordering::Relaxed))
+    }
+
+    pub fn observe(&self, latency: Duration) {
+        let now = STATS_EPOCH.elapsed().as_micros() as u64;
+        let last = self.updated.swap(now, Ordering::Relaxed);
+        let sample = latency.as_micros() as f64;
+        let current = self.ewma();
+        // peak: a slower sample replaces the average right away, faster samples decay into it
+        let ewma = if sample > current {
+            sample
+        } else {
+            let elapsed = now.saturating_sub(last) as f64;
+            let w = (-elapsed / EWMA_DECAY.as_micros() as f64).exp();
+            current * w + sample * (1.0 - w)
+        };
+        self.ewma.store(ewma.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn start_slow_start(&self) {
+        let now = STATS_EPOCH.elapsed().as_micros() as u64;
+        self.slow_start.store(now.max(1), Ordering::Relaxed);
+    }
+
+    // share of the configured weight, ramps linearly from `SLOW_START_MIN` to 1 over `duration`
+    pub fn slow_start_factor(&self, duration: Duration) -> f64 {
+        let start = self.slow_start.load(Ordering::Relaxed);
+        if start == 0 {
+            return 1.0;
+        }
+        let elapsed = (STATS_EPOCH.elapsed().as_micros() as u64).saturating_sub(start);
+        let duration = duration.as_micros() as u64;
+        if elapsed >= duration {
+            self.slow_start.store(0, Ordering::Relaxed);
+            return 1.0;
+        }
+        (elapsed as f64 / duration as f64).max(SLOW_START_MIN)
+    }
+
+    // returns true when the backend recovered since the last check
+    pub fn set_healthy(&self, healthy: bool) -> bool {
+        self.unhealthy.swap(!healthy, Ordering::Relaxed) && healthy
+    }
+}
+
+// Marks a backup endpoint in `Backend::ext`
+#[derive(Debug, Clone)]
+pub struct BackupBackend;
+
+pub fn is_backup(backend: &Backend) -> bool {
+    backend.ext.get::<BackupBackend>().is_some()
+}
+
+fn in_flight(backend: &Backend) -> f64 {
+    BackendStats::get(backend)
+        .map(|s| s.in_flight())
+        .unwrap_or_default() as f64
+}
+
+fn weight(backend: &Backend) -> f64 {
+    backend.weight.max(1) as f64
+}
+
+// Yields backends in a precomputed order
+pub struct OrderedIter {
+    backends: Arc<[Backend]>,
+    order: Vec<usize>,
+    position: usize,
+}
+
+impl BackendIter for OrderedIter {
+    fn next(&mut self) -> Option<&Backend> {
+        let index = *self.order.get(self.position)?;
+        self.position += 1;
+        self.backends.get(index)
+    }
+}
+
+// Weighted least connections: the backend with the fewest in-flight requests per weight wins.
+pub struct LeastConnections {
+    backends: Arc<[Backend]>,
+    // rotates the starting point so ties are spread across backends
+    counter: AtomicUsize,
+}
+
+impl BackendSelection for LeastConnections {
+    type Iter = OrderedIter;
+
+    fn build(backends: &BTreeSet<Backend>) -> Self {
+        Self {
+            backends: backends.iter().cloned().collect(),
+            counter: AtomicUsize::new(0),
+        }
+    }
+
+    fn iter(self: &Arc<Self>, _key: &[u8]) -> Self::Iter {
+        let len = self.backends.len();
+        let offset = self.counter.fetch_add(1, Ordering::Relaxed);
+        let mut order: Vec<usize> = (0..len).map(|i| (i + offset) % len).collect();
+        let score = |i: &usize| {
+            let backend = &self.backends[*i];
+            (in_flight(backend) + 1.0) / weight(backend)
+        };
+        // stable sort keeps the rotation for backends with the same score
+        order.sort_by(|a, b| score(a).total_cmp(&score(b)));
+        OrderedIter {
+            backends: self.backends.clone(),
+            order,
+            position: 0,
+        }
+    }
+}
+
+// Power of two choices on the peak ewma latency:
+// two random backends are compared and the one with the lower `latency * load / weight` wins.
+pub struct PeakEwma {
+    backends: Arc<[Backend]>,
+}
+
+impl BackendSelection for PeakEwma {
+    type Iter = OrderedIter;
+
+    fn build(backends: &BTreeSet<Backend>) -> Self {
+        Self {
+            backends: backends.iter().cloned().collect(),
+        }
+    }
+
+    fn iter(self: &Arc<Self>, _key: &[u8]) -> Self::Iter {
+        let len = self.backends.len();
+        let mut order: Vec<usize> = Vec::with_capacity(len);
+        if len > 0 {
+            let mut rng = rand::thread_rng();
+            let first = rng.gen_range(0..len);
+            // the remaining backends are tried in order after the two choices
+            order.extend((0..len).map(|i| (i + first) % len));
+            if len > 1 {
+                let second = rng.gen_range(1..len);
+                order.swap(1, second);
+                let score = |i: usize| {
+                    let backend = &self.backends[i];
+                    let ewma = BackendStats::get(backend)
+                        .map(|s| s.ewma())
+                        .unwrap_or_default();
+                    (ewma + 1.0) * (in_flight(backend) + 1.0) / weight(backend)
+                };
+                if score(order[1]) < score(order[0]) {
+                    order.swap(0, 1);
+                }
+            }
+        }
+        OrderedIter {
+            backends: self.backends.clone(),
+            order,
+            position: 0,
+        }
+    }
+}
+
+// Weighted consistent hashing on a ring of points keyed by the backend address.
+// Unlike pingora's ketama ring, which only holds inet addresses, unix sockets are included.
+pub struct Ketama {
+    backends: Arc<[Backend]>,
+    // (point, backend index), sorted by point
+    ring: Vec<(u64, usize)>,
+}
+
+fn ring_hash(data: &[u8]) -> u64 {
+    let digest = Sha256::digest(data);
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+impl BackendSelection for Ketama {
+    type Iter = RingIter;
+
+    fn build(backends: &BTreeSet<Backend>) -> Self {
+        let backends: Arc<[Backend]> = backends.iter().cloned().collect();
+        let mut ring = Vec::new();
+        for (index, backend) in backends.iter().enumerate() {
+            let id = backend.addr.to_string();
+            for point in 0..RING_POINTS * backend.weight.max(1) {
+                ring.push((ring_hash(format!("{}-{}", id, point).as_bytes()), index));
+            }
+        }
+        ring.sort_unstable();
+        Self { backends, ring }
+    }
+
+    fn iter(self: &Arc<Self>, key: &[u8]) -> Self::Iter {
+        let hash = ring_hash(key);
+        RingIter {
+            ring: self.clone(),
+            position: self.ring.partition_point(|(point, _)| *point < hash),
+            walked: 0,
+            seen: vec![false; self.backends.len()],
+        }
+    }
+}
+
+// Walks the ring clockwise from the point of the key, each backend once
+pub struct RingIter {
+    ring: Arc<Ketama>,
+    position: usize,
+    walked: usize,
+    seen: Vec<bool>,
+}
+
+impl BackendIter for RingIter {
+    fn next(&mut self) -> Option<&Backend> {
+        let ring = &self.ring.ring;
+        while self.walked < ring.len() {
+            let (_, index) = ring[(self.position + self.walked) % ring.len()];
+            self.walked += 1;
+            if !self.seen[index] {
+                self.seen[index] = true;
+                return self.ring.backends.get(index);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backends(addrs: &[&str]) -> BTreeSet<Backend> {
+        addrs
+            .iter()
+            .map(|addr| {
+                let mut backend = Backend {
+                    addr: addr.parse().unwrap(),
+                    weight: 1,
+                    ext: Default::default(),
+                };
+                backend
+                    .ext
+                    .insert::<Arc<BackendStats>>(Arc::new(BackendStats::default()));
+                backend
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_least_connections() {
+        let backends = backends(&["127.0.0.1:3000", "127.0.0.1:3001"]);
+        let busy = backends.iter().next().unwrap();
+        let stats = BackendStats::get(busy).unwrap();
+        stats.start();
+        stats.start();
+
+        let selection = Arc::new(LeastConnections::build(&backends));
+        for _ in 0..4 {
+            let mut iter = selection.iter(b"");
+            assert_eq!(iter.next().unwrap().addr.to_string(), "127.0.0.1:3001");
+        }
+
+        stats.finish();
+        stats.finish();
+        stats.finish();
+        assert_eq!(stats.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_ketama() {
+        let backends = backends(&[
+            "127.0.0.1:3000",
+            "127.0.0.1:3001",
+            "unix:/run/easy-proxy-a.sock",
+            "unix:/run/easy-proxy-b.sock",
+        ]);
+        let selection = Arc::new(Ketama::build(&backends));
+        let first = |key: &str| {
+            selection
+                .iter(key.as_bytes())
+                .next()
+                .unwrap()
+                .addr
+                .to_string()
+        };
+        // a key always maps to the same backend, and keys spread over all of them
+        let mut selected = BTreeSet::new();
+        for i in 0..200 {
+            let key = format!("client-{}", i);
+            assert_eq!(first(&key), first(&key));
+            selected.insert(first(&key));
+        }
+        assert_eq!(selected.len(), 4);
+        // every backend is yielded once as a fallback
+        let mut iter = selection.iter(b"client-0");
+        let mut all = BTreeSet::new();
+        while let Some(backend) = iter.next() {
+            assert!(all.insert(backend.addr.to_string()));
+        }
+        assert_eq!(all.len(), 4);
+
+        // removing a backend only moves its own keys
+        let fewer = backends
+            .iter()
+            .filter(|b| b.addr.to_string() != "127.0.0.1:3000")
+            .cloned()
+            .collect();
+        let fewer = Arc::new(Ketama::build(&fewer));
+        for i in 0..200 {
+            let key = format!("client-{}", i);
+            let before = first(&key);
+            if before != "127.0.0.1:3000" {
+                assert_eq!(
+                    fewer.iter(key.as_bytes()).next().unwrap().addr.to_string(),
+                    before
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_slow_start() {
+        let stats = BackendStats::default();
+        assert_eq!(stats.slow_start_factor(Duration::from_secs(60)), 1.0);
+        stats.start_slow_start();
+        assert_eq!(
+            stats.slow_start_factor(Duration::from_secs(60)),
+            SLOW_START_MIN
+        );
+        // finished ramps are reset
+        assert_eq!(stats.slow_start_factor(Duration::ZERO), 1.0);
+        assert_eq!(stats.slow_start_factor(Duration::from_secs(60)), 1.0);
+
+        assert!(!stats.set_healthy(true));
+        assert!(!stats.set_healthy(false));
+        assert!(stats.set_healthy(true));
+    }
+
+    #[test]
+    fn test_peak_ewma() {
+        let backends = backends(&["127.0.0.1:3000", "127.0.0.1:3001"]);
+        let slow = backends.iter().next().unwrap();
+        BackendStats::get(slow)
+            .unwrap()
+            .observe(Duration::from_millis(500));
+
+        // with two backends both are always compared
+        let selection = Arc::new(PeakEwma::build(&backends));
+        for _ in 0..4 {
+            let mut iter = selection.iter(b"");
+            assert_eq!(iter.next().unwrap().addr.to_string(), "127.0.0.1:3001");
+            assert_eq!(iter.next().unwrap().addr.to_string(), "127.0.0.1:3000");
+            assert!(iter.next().is_none());
+        }
+    }
+}
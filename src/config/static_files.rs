@@ -0,0 +1,302 @@
+use super::proxy::StaticFiles as StaticFilesConfig;
+use crate::errors::Errors;
+use std::{
+    fs::Metadata,
+    path::{Component, Path, PathBuf},
+};
+
+static DEFAULT_INDEX: &str = "index.html";
+
+// content types by file extension
+static MIME_TYPES: [(&str, &str); 32] = [
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("xml", "application/xml"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+];
+
+pub enum Resolved {
+    File(PathBuf, Metadata),
+    // a directory requested without the trailing slash
+    Redirect,
+    NotFound,
+}
+
+// Files of a `static` service, requests never leave `root`
+#[derive(Debug)]
+pub struct StaticFiles {
+    root: PathBuf,
+    index: Vec<String>,
+    spa: bool,
+    pub precompressed: bool,
+}
+
+impl StaticFiles {
+    pub fn new(service: &str, config: Option<&StaticFilesConfig>) -> Result<Self, Errors> {
+        let Some(config) = config else {
+            return Err(Errors::ConfigError(format!(
+                "Static service {} requires a `static` block",
+                service
+            )));
+        };
+        let root = std::fs::canonicalize(&config.root).map_err(|e| {
+            Errors::ConfigError(format!(
+                "Invalid root {} of static service {}: {}",
+                config.root, service, e
+            ))
+        })?;
+        if !root.is_dir() {
+            return Err(Errors::ConfigError(format!(
+                "Root {} of static service {} is not a directory",
+                config.root, service
+            )));
+        }
+        Ok(Self {
+            root,
+            index: config
+                .index
+                .clone()
+                .unwrap_or_else(|| vec![DEFAULT_INDEX.to_string()]),
+            spa: config.spa.unwrap_or(false),
+            precompressed: config.precompressed.unwrap_or(false),
+        })
+    }
+
+    // map a request path onto a file below the root
+    pub async fn resolve(&self, path: &str) -> Resolved {
+        match self.lookup(path).await {
+            Resolved::NotFound if self.spa => self.index(&self.root).await,
+            resolved => resolved,
+        }
+    }
+
+    async fn lookup(&self, path: &str) -> Resolved {
+        let Some(decoded) = percent_decode(path) else {
+            return Resolved::NotFound;
+        };
+        let mut file = self.root.clone();
+        for component in Path::new(&decoded).components() {
+            match component {
+                Component::Normal(part) => file.push(part),
+                Component::RootDir | Component::CurDir => {}
+                // `..` and prefixes are never followed
+                _ => return Resolved::NotFound,
+            }
+        }
+        let Ok(meta) = tokio::fs::metadata(&file).await else {
+            return Resolved::NotFound;
+        };
+        if meta.is_dir() {
+            if !path.ends_with('/') {
+                return Resolved::Redirect;
+            }
+            return self.index(&file).await;
+        }
+        match self.file(&file).await {
+            Some((path, meta)) => Resolved::File(path, meta),
+            None => Resolved::NotFound,
+        }
+    }
+
+    // the first index file of a directory
+    async fn index(&self, dir: &Path) -> Resolved {
+        for index in self.index.iter() {
+            if let Some((path, meta)) = self.file(&dir.join(index)).await {
+                return Resolved::File(path, meta);
+            }
+        }
+        Resolved::NotFound
+    }
+
+    // a regular file that is still below the root once symlinks are followed
+    pub async fn file(&self, path: &Path) -> Option<(PathBuf, Metadata)> {
+        let path = tokio::fs::canonicalize(path).await.ok()?;
+        if !path.starts_with(&self.root) {
+            return None;
+        }
+        let meta = tokio::fs::metadata(&path).await.ok()?;
+        meta.is_file().then_some((path, meta))
+    }
+}
+
+// `Location` of a directory requested without the trailing slash, built from the request
+// URI as the resolved path may be rewritten below a prefix of the route
+pub fn directory_location(uri: &http::Uri) -> String {
+    match uri.query() {
+        Some(query) => format!("{}/?{}", uri.path(), query),
+        None => format!("{}/", uri.path()),
+    }
+}
+
+pub fn mime_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .unwrap_or_default();
+    MIME_TYPES
+        .iter()
+        .find(|(e, _)| *e == ext)
+        .map(|(_, t)| *t)
+        .unwrap_or("application/octet-stream")
+}
+
+// decode `%XX` escapes, `None` for invalid escapes, invalid UTF-8 or NUL bytes
+pub fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    if out.contains(&0) {
+        return None;
+    }
+    String::from_utf8(out).ok()
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Range {
+    // first and last byte, inclusive
+    Bytes(u64, u64),
+    Unsatisfiable,
+}
+
+// byte range of a `Range` header
+// multiple ranges and other units are ignored and the whole file is sent
+pub fn parse_range(header: &str, len: u64) -> Option<Range> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().ok()?;
+            if suffix == 0 || len == 0 {
+                return Some(Range::Unsatisfiable);
+            }
+            (len.saturating_sub(suffix), len - 1)
+        }
+        (start, end) => {
+            let start = start.parse::<u64>().ok()?;
+            let end = match end {
+                "" => len.saturating_sub(1),
+                end => end.parse::<u64>().ok()?.min(len.saturating_sub(1)),
+            };
+            if start >= len || start > end {
+                return Some(Range::Unsatisfiable);
+            }
+            (start, end)
+        }
+    };
+    Some(Range::Bytes(range.0, range.1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let root = std::env::temp_dir().join(format!("easy-proxy-static-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("index.html"), "index").unwrap();
+        std::fs::write(root.join("docs/index.html"), "docs").unwrap();
+        std::fs::write(root.join("app.js"), "js").unwrap();
+        let files = StaticFiles::new(
+            "test",
+            Some(&StaticFilesConfig {
+                root: root.to_string_lossy().to_string(),
+                spa: Some(true),
+                ..Default::default()
+            }),
+        )
+        .unwrap();
+        let file = |path: &'static str| {
+            let files = &files;
+            async move {
+                match files.resolve(path).await {
+                    Resolved::File(p, _) => {
+                        p.strip_prefix(&files.root).unwrap().display().to_string()
+                    }
+                    Resolved::Redirect => "redirect".to_string(),
+                    Resolved::NotFound => "not found".to_string(),
+                }
+            }
+        };
+        assert_eq!(file("/app.js").await, "app.js");
+        assert_eq!(file("/docs/").await, "docs/index.html");
+        assert_eq!(file("/docs").await, "redirect");
+        assert_eq!(file("/%61pp.js").await, "app.js");
+        // unknown paths fall back to the SPA index
+        assert_eq!(file("/users/42").await, "index.html");
+        assert_eq!(file("/../etc/passwd").await, "index.html");
+        assert_eq!(file("/..%2f..%2fetc/passwd").await, "index.html");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_directory_location() {
+        // e.g. `/assets` rewritten to `/` resolves `/assets/img` to the directory `img`
+        let uri: http::Uri = "/assets/img".parse().unwrap();
+        assert_eq!(directory_location(&uri), "/assets/img/");
+        let uri: http::Uri = "/assets/img?v=2".parse().unwrap();
+        assert_eq!(directory_location(&uri), "/assets/img/?v=2");
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Range::Bytes(0, 99)));
+        assert_eq!(
+            parse_range("bytes=900-", 1000),
+            Some(Range::Bytes(900, 999))
+        );
+        assert_eq!(
+            parse_range("bytes=-100", 1000),
+            Some(Range::Bytes(900, 999))
+        );
+        assert_eq!(
+            parse_range("bytes=0-5000", 1000),
+            Some(Range::Bytes(0, 999))
+        );
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(Range::Unsatisfiable));
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+    }
+}
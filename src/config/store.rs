@@ -1,8 +1,20 @@
 use super::{
+    access::AccessList,
     backend::load_backend,
+    basic_auth::BasicAuth,
     certs::load_cert,
-    proxy::{read, Acme, AcmeProvider, Header, Path, ProxyConfig, ServiceReference, Tls, TlsRoute},
+    circuit_breaker::Breaker,
+    cors::CorsPolicy,
+    jwt::JwtValidator,
+    proxy::{
+        read, Acme, AcmeProvider, Cache, Compression, Discovery, ForwardAuth, ForwardedHeaders,
+        Header, Path, ProxyConfig, Redirect, Retry, Return, SecurityHeaders, ServiceReference,
+        Sticky, Tls, TlsRoute,
+    },
+    rate_limit::RateLimiter,
     runtime,
+    selection::{BackendStats, Ketama, LeastConnections, PeakEwma},
+    static_files::StaticFiles,
 };
 use crate::{
     acme::{client::AcmeClient, crypto::AcmeKeyPair},
@@ -14,15 +26,16 @@ use pingora::{
     lb::{
         selection::{
             algorithms::{Random, RoundRobin},
-            consistent::KetamaHashing,
             weighted::Weighted,
         },
-        LoadBalancer,
+        Backends, LoadBalancer,
     },
+    protocols::l4::socket::SocketAddr,
     tls::pkey::PKey,
 };
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::sync::{atomic::AtomicBool, Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{collections::HashMap, sync::LazyLock};
 
 // proxy global store
@@ -123,8 +136,10 @@ pub struct AcmeAccount {
 pub enum BackendType {
     RoundRobin(Arc<LoadBalancer<Weighted<RoundRobin>>>),
     Weighted(Arc<LoadBalancer<Weighted<fnv::FnvHasher>>>),
-    Consistent(Arc<LoadBalancer<KetamaHashing>>),
+    Consistent(Arc<LoadBalancer<Ketama>>),
     Random(Arc<LoadBalancer<Weighted<Random>>>),
+    LeastConn(Arc<LoadBalancer<LeastConnections>>),
+    PeakEwma(Arc<LoadBalancer<PeakEwma>>),
 }
 
 // to string
@@ -139,8 +154,37 @@ impl std::fmt::Display for BackendType {
                 write!(f, "Consistent({:#?})", v.backends().get_backend())
             }
             BackendType::Random(v) => write!(f, "Random({:#?})", v.backends().get_backend()),
+            BackendType::LeastConn(v) => {
+                write!(f, "LeastConn({:#?})", v.backends().get_backend())
+            }
+            BackendType::PeakEwma(v) => write!(f, "PeakEwma({:#?})", v.backends().get_backend()),
+        }
+    }
+}
+
+impl BackendType {
+    pub fn backends(&self) -> &Backends {
+        match self {
+            BackendType::RoundRobin(lb) => lb.backends(),
+            BackendType::Weighted(lb) => lb.backends(),
+            BackendType::Consistent(lb) => lb.backends(),
+            BackendType::Random(lb) => lb.backends(),
+            BackendType::LeastConn(lb) => lb.backends(),
+            BackendType::PeakEwma(lb) => lb.backends(),
         }
     }
+
+    pub async fn update(&self) -> Result<(), Errors> {
+        match self {
+            BackendType::RoundRobin(lb) => lb.update().await,
+            BackendType::Weighted(lb) => lb.update().await,
+            BackendType::Consistent(lb) => lb.update().await,
+            BackendType::Random(lb) => lb.update().await,
+            BackendType::LeastConn(lb) => lb.update().await,
+            BackendType::PeakEwma(lb) => lb.update().await,
+        }
+        .map_err(|e| Errors::PingoraError(format!("{}", e)))
+    }
 }
 
 impl std::fmt::Debug for BackendType {
@@ -153,15 +197,150 @@ impl std::fmt::Debug for BackendType {
 pub struct HttpService {
     pub name: String,
     pub backend_type: BackendType,
+    pub discovery: Option<Discovery>,
+    pub hash_key: Option<String>,
+    pub sticky: Option<Sticky>,
+    pub retry: Option<RetryPolicy>,
+    pub fallback_service: Option<String>,
+    // whether the traffic currently goes to backup endpoints or the fallback service
+    pub failover: Arc<AtomicBool>,
+    pub health_check_interval: Option<Duration>,
+    pub health_checked: Arc<Mutex<Option<Instant>>>,
+    pub slow_start: Option<Duration>,
+    pub circuit_breaker: Option<Arc<Breaker>>,
+}
+
+impl HttpService {
+    pub fn backend_addrs(&self) -> Vec<SocketAddr> {
+        self.backend_type
+            .backends()
+            .get_backend()
+            .iter()
+            .map(|b| b.addr.clone())
+            .collect()
+    }
+
+    // start the slow start of backends that are not in `known`
+    pub fn slow_start_new(&self, known: &[SocketAddr]) {
+        if self.slow_start.is_none() {
+            return;
+        }
+        for b in self.backend_type.backends().get_backend().iter() {
+            if known.contains(&b.addr) {
+                continue;
+            }
+            if let Some(stats) = BackendStats::get(b) {
+                tracing::info!("Slow start of backend {} in service {}", b.addr, self.name);
+                stats.start_slow_start();
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub on_connect_error: bool,
+    pub on_timeout: bool,
+    pub on_error: bool,
+    pub statuses: Vec<u16>,
+    pub methods: Vec<String>,
+    pub per_try_timeout: Option<Duration>,
+}
+
+impl RetryPolicy {
+    pub fn new(service: &str, retry: &Retry) -> Result<Self, Errors> {
+        let on = retry
+            .on
+            .clone()
+            .unwrap_or_else(|| vec!["connect_error".to_string()]);
+        if let Some(on) = on
+            .iter()
+            .find(|o| !matches!(o.as_str(), "connect_error" | "timeout" | "error"))
+        {
+            return Err(Errors::ConfigError(format!(
+                "Invalid retry condition {} for service {}, must be connect_error, timeout or error",
+                on, service
+            )));
+        }
+        let methods = retry.methods.clone().unwrap_or_else(|| {
+            ["GET", "HEAD", "OPTIONS", "PUT", "DELETE", "TRACE"]
+                .iter()
+                .map(|m| m.to_string())
+                .collect()
+        });
+        Ok(RetryPolicy {
+            attempts: retry.attempts.max(1),
+            on_connect_error: on.iter().any(|o| o == "connect_error"),
+            on_timeout: on.iter().any(|o| o == "timeout"),
+            on_error: on.iter().any(|o| o == "error"),
+            statuses: retry.statuses.clone().unwrap_or_default(),
+            methods: methods.iter().map(|m| m.to_ascii_uppercase()).collect(),
+            per_try_timeout: retry.per_try_timeout_ms.map(Duration::from_millis),
+        })
+    }
+}
+
+// response of a route that doesn't go upstream
+#[derive(Debug, Clone)]
+pub enum Action {
+    Return(Return),
+    Redirect(Redirect),
+}
+
+fn action(
+    name: &str,
+    return_response: &Option<Return>,
+    redirect: &Option<Redirect>,
+) -> Result<Option<Action>, Errors> {
+    match (return_response, redirect) {
+        (Some(_), Some(_)) => Err(Errors::ConfigError(format!(
+            "{} can either return a response or redirect",
+            name
+        ))),
+        (Some(r), None) => {
+            if !(100..=599).contains(&r.status.unwrap_or(200)) {
+                return Err(Errors::ConfigError(format!(
+                    "Invalid return status of {}",
+                    name
+                )));
+            }
+            Ok(Some(Action::Return(r.clone())))
+        }
+        (None, Some(r)) => {
+            if !matches!(r.status, None | Some(301 | 302 | 303 | 307 | 308)) {
+                return Err(Errors::ConfigError(format!(
+                    "Invalid redirect status of {}, must be 301, 302, 303, 307 or 308",
+                    name
+                )));
+            }
+            Ok(Some(Action::Redirect(r.clone())))
+        }
+        (None, None) => Ok(None),
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Route {
+    pub action: Option<Action>,
     pub path: Path,
     pub service: ServiceReference,
     pub remove_headers: Option<Vec<String>>,
     pub add_headers: Option<Vec<Header>>,
     pub tls: Option<TlsRoute>,
+    pub rate_limit: Option<Arc<RateLimiter>>,
+    pub access: Option<Arc<AccessList>>,
+    pub forwarded_headers: Option<ForwardedHeaders>,
+    pub basic_auth: Option<Arc<BasicAuth>>,
+    pub jwt: Option<Arc<JwtValidator>>,
+    pub forward_auth: Option<ForwardAuth>,
+    pub cors: Option<Arc<CorsPolicy>>,
+    pub response_add_headers: Option<Vec<Header>>,
+    pub response_set_headers: Option<Vec<Header>>,
+    pub response_remove_headers: Option<Vec<String>>,
+    pub security_headers: Option<SecurityHeaders>,
+    pub compression: Option<Compression>,
+    pub cache: Option<Cache>,
 }
 
 #[derive(Debug, Clone)]
@@ -170,6 +349,10 @@ pub struct ProxyStore {
     pub http_services: HashMap<String, HttpService>,
     pub host_routes: HashMap<String, matchit::Router<Route>>,
     pub header_routes: HashMap<String, matchit::Router<Route>>,
+    pub access: Option<Arc<AccessList>>,
+    // access lists with files, reloaded by the background service
+    pub access_lists: Vec<Arc<AccessList>>,
+    pub static_services: HashMap<String, Arc<StaticFiles>>,
 }
 
 pub fn acme_store() -> Result<AcmeStore, Errors> {
@@ -216,20 +399,89 @@ pub async fn load(
         http_services: HashMap::new(),
         host_routes: HashMap::new(),
         header_routes: HashMap::new(),
+        access: None,
+        access_lists: Vec::new(),
+        static_services: HashMap::new(),
     };
     let mut tls_configs: HashMap<String, TlsGlobalConfig> = HashMap::new();
 
     // Process services
     for config in configs.iter() {
         for service in config.services.iter().flatten() {
+            if service.service_type == "static" {
+                let files = StaticFiles::new(&service.name, service.static_files.as_ref())?;
+                store
+                    .static_services
+                    .insert(service.name.clone(), Arc::new(files));
+                continue;
+            }
+            if let Some(sticky) = &service.sticky {
+                if sticky.secret.is_empty() {
+                    return Err(Errors::ConfigError(format!(
+                        "Sticky sessions of service {} require a secret",
+                        service.name
+                    )));
+                }
+                if !matches!(
+                    sticky.same_site.as_deref(),
+                    None | Some("Strict") | Some("Lax") | Some("None")
+                ) {
+                    return Err(Errors::ConfigError(format!(
+                        "Invalid same_site for service {}, must be Strict, Lax or None",
+                        service.name
+                    )));
+                }
+            }
+            if service.slow_start.is_some()
+                && !matches!(service.algorithm.as_str(), "round_robin" | "weighted")
+            {
+                return Err(Errors::ConfigError(format!(
+                    "Slow start of service {} requires the round_robin or weighted algorithm",
+                    service.name
+                )));
+            }
             let svc = HttpService {
                 name: service.name.clone(),
                 backend_type: load_backend(service, &service.endpoints).await?,
+                discovery: service.discovery.clone(),
+                hash_key: service.hash_key.clone(),
+                sticky: service.sticky.clone(),
+                retry: match &service.retry {
+                    Some(retry) => Some(RetryPolicy::new(&service.name, retry)?),
+                    None => None,
+                },
+                fallback_service: service.fallback_service.clone(),
+                failover: Arc::new(AtomicBool::new(false)),
+                health_check_interval: service
+                    .health_check
+                    .as_ref()
+                    .map(|h| Duration::from_secs(h.interval.unwrap_or(5))),
+                health_checked: Arc::new(Mutex::new(None)),
+                slow_start: service.slow_start.map(Duration::from_secs),
+                circuit_breaker: match &service.circuit_breaker {
+                    Some(cb) => Some(Arc::new(Breaker::new(&service.name, cb)?)),
+                    None => None,
+                },
             };
+            // endpoints added by a reload start slowly, the first load starts at full weight
+            if let Some(previous) = get().and_then(|s| s.http_services.get(&svc.name)) {
+                svc.slow_start_new(&previous.backend_addrs());
+            }
             store.http_services.insert(svc.name.clone(), svc);
         }
     }
 
+    for svc in store.http_services.values() {
+        if let Some(fallback) = &svc.fallback_service {
+            if !store.http_services.contains_key(fallback) {
+                return Err(Errors::ConfigError(format!(
+                    "Fallback service {} of service {} not found",
+                    fallback, svc.name
+                )));
+            }
+        }
+    }
+
     // Process tls
     let tls: Vec<Tls> = configs
         .iter()
@@ -246,6 +498,17 @@ pub async fn load(
         } else if let Some(selector) = &config.header_selector {
             store.header_selector = selector.clone();
         }
+        if store.access.is_some() && config.access.is_some() {
+            tracing::warn!(
+                "Multiple global access lists found in config files. Using the first one."
+            );
+        } else if let Some(access) = &config.access {
+            let access = Arc::new(AccessList::new("global", access)?);
+            if access.has_files() {
+                store.access_lists.push(access.clone());
+            }
+            store.access = Some(access);
+        }
         for route in config.routes.iter().flatten() {
             if route.route.condition_type == *"host" {
                 if let Some(r_tls) = &route.tls {
@@ -280,15 +543,110 @@ pub async fn load(
                     }
                 }
             }
+            // shared by all paths of the route
+            let rate_limit = match &route.rate_limit {
+                Some(rl) => Some(Arc::new(RateLimiter::new(&route.name, rl)?)),
+                None => None,
+            };
+            if let Some(fh) = &route.forwarded_headers {
+                if !matches!(
+                    fh.mode.as_deref(),
+                    None | Some("append" | "replace" | "off")
+                ) {
+                    return Err(Errors::ConfigError(format!(
+                        "Invalid forwarded_headers mode for route {}, must be append, replace or off",
+                        route.name
+                    )));
+                }
+            }
+            let basic_auth = match &route.basic_auth {
+                Some(auth) => Some(Arc::new(BasicAuth::new(&route.name, auth)?)),
+                None => None,
+            };
+            if let Some(fa) = &route.forward_auth {
+                if !store.http_services.contains_key(&fa.service) {
+                    return Err(Errors::ConfigError(format!(
+                        "Forward auth service {} of route {} not found",
+                        fa.service, route.name
+                    )));
+                }
+            }
+            if let Some(compression) = &route.compression {
+                if let Some(name) = compression
+                    .algorithms
+                    .iter()
+                    .flatten()
+                    .find(|a| !matches!(a.as_str(), "gzip" | "br" | "zstd"))
+                {
+                    return Err(Errors::ConfigError(format!(
+                        "Invalid compression algorithm {} for route {}, must be gzip, br or zstd",
+                        name, route.name
+                    )));
+                }
+            }
+            let cors = match &route.cors {
+                Some(cors) => Some(Arc::new(CorsPolicy::new(&route.name, cors)?)),
+                None => None,
+            };
+            let jwt = match &route.jwt {
+                Some(jwt) => Some(Arc::new(JwtValidator::new(&route.name, jwt)?)),
+                None => None,
+            };
+            let access = match &route.access {
+                Some(access) => {
+                    let access = Arc::new(AccessList::new(&route.name, access)?);
+                    if access.has_files() {
+                        store.access_lists.push(access.clone());
+                    }
+                    Some(access)
+                }
+                None => None,
+            };
+            let route_action = action(
+                &format!("Route {}", route.name),
+                &route.return_response,
+                &route.redirect,
+            )?;
+            let mut paths = route.paths.clone().unwrap_or_default();
+            // a route that only returns or redirects matches every path
+            if paths.is_empty() && route_action.is_some() {
+                paths.push(Path {
+                    path_type: "Prefix".to_string(),
+                    path: "/".to_string(),
+                    service: ServiceReference::default(),
+                    return_response: None,
+                    redirect: None,
+                });
+            }
             let mut routes = matchit::Router::<Route>::new();
-            for path in route.paths.iter().flatten() {
+            for path in paths.iter() {
                 let path_type = path.path_type.clone();
+                let name = format!("Path {} of route {}", path.path, route.name);
+                let path_action = action(&name, &path.return_response, &path.redirect)?
+                    .or_else(|| route_action.clone());
+                if path_action.is_none() && path.service.name.is_empty() {
+                    return Err(Errors::ConfigError(format!("{} requires a service", name)));
+                }
                 let r = Route {
+                    action: path_action,
                     path: path.clone(),
                     service: path.service.clone(),
                     remove_headers: route.remove_headers.clone(),
                     add_headers: route.add_headers.clone(),
                     tls: route.tls.clone(),
+                    rate_limit: rate_limit.clone(),
+                    access: access.clone(),
+                    forwarded_headers: route.forwarded_headers.clone(),
+                    basic_auth: basic_auth.clone(),
+                    jwt: jwt.clone(),
+                    forward_auth: route.forward_auth.clone(),
+                    cors: cors.clone(),
+                    response_add_headers: route.response_add_headers.clone(),
+                    response_set_headers: route.response_set_headers.clone(),
+                    response_remove_headers: route.response_remove_headers.clone(),
+                    security_headers: route.security_headers.clone(),
+                    compression: route.compression.clone(),
+                    cache: route.cache.clone(),
                 };
                 match routes.insert(path.path.clone(), r.clone()) {
                     Ok(_) => {}
@@ -380,6 +738,102 @@ pub fn get_tls() -> Option<&'static HashMap<String, TlsGlobalConfig>> {
     }
 }
 
+// refresh the backends of services that use dynamic discovery
+// - each service is updated in its own task so a slow catalog doesn't block the others
+pub async fn update_discovery() {
+    let Some(store) = get() else {
+        return;
+    };
+    for svc in store.http_services.values() {
+        if svc.discovery.is_none() {
+            continue;
+        }
+        tokio::spawn(async move {
+            let known = svc.backend_addrs();
+            if let Err(e) = svc.backend_type.update().await {
+                tracing::error!("Unable to update backends of service {}: {}", svc.name, e);
+            }
+            svc.slow_start_new(&known);
+        });
+    }
+}
+
+// runtime status of the services, one line per service
+pub fn status() -> String {
+    let Some(store) = get() else {
+        return "No configuration loaded".to_string();
+    };
+    let mut services: Vec<&HttpService> = store.http_services.values().collect();
+    services.sort_by(|a, b| a.name.cmp(&b.name));
+    services
+        .iter()
+        .map(|svc| {
+            let backends = svc.backend_type.backends();
+            let all = backends.get_backend();
+            let healthy = all.iter().filter(|b| backends.ready(b)).count();
+            let mut line = format!("{}: backends {}/{} healthy", svc.name, healthy, all.len());
+            if let Some(breaker) = &svc.circuit_breaker {
+                line.push_str(&format!(
+                    ", circuit breaker {} ({} in flight)",
+                    breaker.state(),
+                    breaker.in_flight()
+                ));
+            }
+            line
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// re-read the access list files that changed
+pub fn reload_access_lists() {
+    let Some(store) = get() else {
+        return;
+    };
+    for access in store.access_lists.iter() {
+        access.reload();
+    }
+}
+
+// run the active health checks of the services that are due
+pub async fn run_health_checks() {
+    let Some(store) = get() else {
+        return;
+    };
+    for svc in store.http_services.values() {
+        let Some(interval) = svc.health_check_interval else {
+            continue;
+        };
+        {
+            let mut checked = match svc.health_checked.lock() {
+                Ok(val) => val,
+                Err(e) => e.into_inner(),
+            };
+            if checked.is_some_and(|t| t.elapsed() < interval) {
+                continue;
+            }
+            *checked = Some(Instant::now());
+        }
+        tokio::spawn(async move {
+            let backends = svc.backend_type.backends();
+            backends.run_health_check(true).await;
+            for b in backends.get_backend().iter() {
+                let Some(stats) = BackendStats::get(b) else {
+                    continue;
+                };
+                if stats.set_healthy(backends.ready(b)) && svc.slow_start.is_some() {
+                    tracing::info!(
+                        "Slow start of recovered backend {} in service {}",
+                        b.addr,
+                        svc.name
+                    );
+                    stats.start_slow_start();
+                }
+            }
+        });
+    }
+}
+
 // ACME_REQUEST_QUEUE
 // - key: tls name
 // - value: email, vec<domain>
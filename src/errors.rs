@@ -14,6 +14,9 @@ pub enum Errors {
     #[error("Proxy error: {0}")]
     ProxyError(String),
 
+    #[error("Service unavailable: {0}")]
+    ServiceUnavailable(String),
+
     #[error("ACME key pair error: {0}")]
     AcmeKeyPairError(String),
 
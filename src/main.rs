@@ -25,6 +25,14 @@ struct Args {
     /// Reload the configuration.
     #[arg(short, long, default_value_t = false)]
     reload: bool,
+
+    /// Show the status of the services.
+    #[arg(short, long, default_value_t = false)]
+    status: bool,
+
+    /// Purge a cached response by its key, e.g. example.com/index.html
+    #[arg(short, long)]
+    purge: Option<String>,
 }
 
 fn main() {
@@ -44,6 +52,16 @@ fn main() {
         std::process::exit(0);
     }
 
+    if args.status {
+        Commands::send_command("status");
+        std::process::exit(0);
+    }
+
+    if let Some(key) = args.purge {
+        Commands::send_command(&format!("purge {}", key));
+        std::process::exit(0);
+    }
+
     // Initialize configuration.
     if let Err(e) = config::runtime::initialize() {
         tracing::error!("Error initializing configuration: {:?}", e);
@@ -0,0 +1,44 @@
+use super::{context::Context, response::Response, variables};
+use crate::config::store::Action;
+use bytes::Bytes;
+
+// answer the request without an upstream
+pub async fn run(res: &mut Response<'_>, action: &Action, ctx: &Context) -> pingora::Result<bool> {
+    match action {
+        Action::Return(r) => {
+            res.status(r.status.unwrap_or(200));
+            let mut content_type = false;
+            for header in r.headers.iter().flatten() {
+                content_type |= header.name.eq_ignore_ascii_case("content-type");
+                let value = variables::expand(&header.value, res.session, ctx);
+                res.header(&header.name, &value);
+            }
+            let body = r
+                .body
+                .as_deref()
+                .map(|b| variables::expand(b, res.session, ctx))
+                .unwrap_or_default();
+            if !body.is_empty() && !content_type {
+                res.header("Content-Type", "text/plain; charset=utf-8");
+            }
+            res.body(Bytes::from(body)).route_headers(ctx)?.send().await
+        }
+        Action::Redirect(r) => {
+            let mut location = variables::expand(&r.to, res.session, ctx);
+            let uri = &res.session.req_header().uri;
+            if r.preserve_path.unwrap_or(false) {
+                location = format!("{}{}", location.trim_end_matches('/'), uri.path());
+            }
+            if r.preserve_query.unwrap_or(false) {
+                if let Some(query) = uri.query() {
+                    location.push(if location.contains('?') { '&' } else { '?' });
+                    location.push_str(query);
+                }
+            }
+            res.redirect(&location, r.status.unwrap_or(302))
+                .route_headers(ctx)?
+                .send()
+                .await
+        }
+    }
+}
@@ -1,13 +1,318 @@
-use crate::config::store::{BackendType, HttpService};
+use crate::config::{
+    self,
+    selection::{is_backup, BackendStats},
+    store::{BackendType, HttpService},
+};
 use crate::errors::Errors;
+use fnv::FnvHasher;
 use pingora::lb::Backend;
+use std::{hash::Hasher, sync::atomic::Ordering, time::Duration};
 
-pub fn selection(selection_key: &str, service: &HttpService) -> Result<Backend, Errors> {
+pub fn selection<'a>(
+    selection_key: &str,
+    service: &'a HttpService,
+) -> Result<(Backend, &'a HttpService), Errors> {
+    selection_with(selection_key, service, |_, healthy| healthy)
+}
+
+// select a backend that passes `accept(backend, healthy)` and has room for another request:
+// primary endpoints first, then backup endpoints, then the fallback service
+pub fn selection_with<'a, F>(
+    selection_key: &str,
+    service: &'a HttpService,
+    accept: F,
+) -> Result<(Backend, &'a HttpService), Errors>
+where
+    F: Fn(&Backend, bool) -> bool,
+{
+    if let Some(b) = select(selection_key, service, |b, healthy| {
+        !is_backup(b) && accept(b, healthy)
+    }) {
+        if service.failover.swap(false, Ordering::Relaxed) {
+            tracing::info!(
+                "Service {} recovered, primary endpoints available",
+                service.name
+            );
+        }
+        return Ok((b, service));
+    }
+    if let Some(b) = select(selection_key, service, |b, healthy| {
+        is_backup(b) && accept(b, healthy)
+    }) {
+        if !service.failover.swap(true, Ordering::Relaxed) {
+            tracing::warn!("Service {} failed over to backup endpoints", service.name);
+        }
+        return Ok((b, service));
+    }
+    // the fallback service is used as is, its own fallback is not followed
+    let fallback = service
+        .fallback_service
+        .as_ref()
+        .and_then(|name| config::store::get()?.http_services.get(name));
+    if let Some(fallback) = fallback {
+        if let Some(b) = select(selection_key, fallback, &accept) {
+            if !service.failover.swap(true, Ordering::Relaxed) {
+                tracing::warn!(
+                    "Service {} failed over to fallback service {}",
+                    service.name,
+                    fallback.name
+                );
+            }
+            return Ok((b, fallback));
+        }
+    }
+    // busy endpoints are a transient condition the client may retry
+    let full = |service: &HttpService| {
+        service
+            .backend_type
+            .backends()
+            .get_backend()
+            .iter()
+            .any(|b| BackendStats::get(b).is_some_and(|s| !s.has_capacity()))
+    };
+    if full(service) || fallback.is_some_and(full) {
+        return Err(Errors::ServiceUnavailable(format!(
+            "Endpoints of service {} are at max_connections",
+            service.name
+        )));
+    }
+    Err(Errors::ConfigError("No backend found".to_string()))
+}
+
+fn select<F>(selection_key: &str, service: &HttpService, accept: F) -> Option<Backend>
+where
+    F: Fn(&Backend, bool) -> bool,
+{
+    let accept = |backend: &Backend, healthy: bool| {
+        accept(backend, healthy)
+            && BackendStats::get(backend)
+                .map(|s| s.has_capacity())
+                .unwrap_or(true)
+    };
+    if let Some(slow_start) = service.slow_start {
+        if let Some(b) = select_slow_start(selection_key, service, slow_start, &accept) {
+            return Some(b);
+        }
+    }
+    select_from(selection_key, service, accept)
+}
+
+// while a backend ramps up, pick by effective weight (configured weight times the slow
+// start factor) instead of the algorithm's table, which only knows the configured weights.
+// `None` when no candidate is in slow start, the algorithm then selects as usual
+fn select_slow_start<F>(
+    selection_key: &str,
+    service: &HttpService,
+    slow_start: Duration,
+    accept: F,
+) -> Option<Backend>
+where
+    F: Fn(&Backend, bool) -> bool,
+{
+    let backends = service.backend_type.backends();
+    let all = backends.get_backend();
+    let mut candidates = Vec::new();
+    let mut weights = Vec::new();
+    let mut ramping = false;
+    for backend in all.iter() {
+        if !accept(backend, backends.ready(backend)) {
+            continue;
+        }
+        let factor = BackendStats::get(backend)
+            .map(|s| s.slow_start_factor(slow_start))
+            .unwrap_or(1.0);
+        ramping |= factor < 1.0;
+        candidates.push(backend);
+        weights.push(effective_weight(backend.weight, factor));
+    }
+    if !ramping {
+        return None;
+    }
+    // weighted keeps the key affinity of its hash, round robin spreads at random
+    let point = match &service.backend_type {
+        BackendType::Weighted(_) => {
+            let mut hasher = FnvHasher::default();
+            hasher.write(selection_key.as_bytes());
+            hasher.finish() as f64 / (u64::MAX as f64 + 1.0)
+        }
+        _ => rand::random::<f64>(),
+    };
+    weighted_index(&weights, point).map(|i| candidates[i].clone())
+}
+
+// weight of a ramping backend, never below 1 so it keeps getting some traffic
+fn effective_weight(weight: usize, factor: f64) -> f64 {
+    (weight as f64 * factor).max(1.0)
+}
+
+// index of the weight that covers `point` (in [0, 1)) of the total weight
+fn weighted_index(weights: &[f64], point: f64) -> Option<usize> {
+    let total: f64 = weights.iter().sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let mut point = point * total;
+    for (i, weight) in weights.iter().enumerate() {
+        if point < *weight {
+            return Some(i);
+        }
+        point -= weight;
+    }
+    // rounding left the point past the end
+    weights.iter().rposition(|w| *w > 0.0)
+}
+
+fn select_from<F>(selection_key: &str, service: &HttpService, accept: F) -> Option<Backend>
+where
+    F: Fn(&Backend, bool) -> bool,
+{
+    let key = selection_key.as_bytes();
     match &service.backend_type {
-        BackendType::RoundRobin(lb) => lb.select(selection_key.as_bytes(), 256),
-        BackendType::Weighted(lb) => lb.select(selection_key.as_bytes(), 256),
-        BackendType::Consistent(lb) => lb.select(selection_key.as_bytes(), 256),
-        BackendType::Random(lb) => lb.select(selection_key.as_bytes(), 256),
+        BackendType::RoundRobin(lb) => lb.select_with(key, 256, accept),
+        BackendType::Weighted(lb) => lb.select_with(key, 256, accept),
+        BackendType::Consistent(lb) => lb.select_with(key, 256, accept),
+        BackendType::Random(lb) => lb.select_with(key, 256, accept),
+        BackendType::LeastConn(lb) => lb.select_with(key, 256, accept),
+        BackendType::PeakEwma(lb) => lb.select_with(key, 256, accept),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{backend::load_backend, proxy::Service};
+    use std::{
+        net::TcpListener,
+        sync::{atomic::AtomicBool, Arc, Mutex},
+    };
+
+    #[tokio::test]
+    async fn test_backup_selection() {
+        let mut primaries: Vec<TcpListener> = (0..2)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let backup = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = |l: &TcpListener| l.local_addr().unwrap().to_string();
+        let port = |l: &TcpListener| l.local_addr().unwrap().port();
+        let config = format!(
+            r#"
+name: test
+type: http
+algorithm: round_robin
+health_check:
+  type: tcp
+endpoints:
+  - ip: 127.0.0.1
+    port: {}
+  - ip: 127.0.0.1
+    port: {}
+  - ip: 127.0.0.1
+    port: {}
+    backup: true
+"#,
+            port(&primaries[0]),
+            port(&primaries[1]),
+            port(&backup)
+        );
+        let service: Service = serde_yml::from_str(&config).unwrap();
+        let svc = HttpService {
+            name: service.name.clone(),
+            backend_type: load_backend(&service, &service.endpoints).await.unwrap(),
+            discovery: None,
+            hash_key: None,
+            sticky: None,
+            retry: None,
+            fallback_service: None,
+            failover: Arc::new(AtomicBool::new(false)),
+            health_check_interval: None,
+            health_checked: Arc::new(Mutex::new(None)),
+            slow_start: None,
+            circuit_breaker: None,
+        };
+        let selected = || selection("", &svc).unwrap().0.addr.to_string();
+        let backup_addr = addr(&backup);
+        for _ in 0..4 {
+            assert_ne!(selected(), backup_addr);
+        }
+
+        // the remaining primary takes all of the traffic
+        drop(primaries.remove(0));
+        svc.backend_type.backends().run_health_check(false).await;
+        for _ in 0..4 {
+            assert_eq!(selected(), addr(&primaries[0]));
+        }
+        assert!(!svc.failover.load(Ordering::Relaxed));
+
+        // the backup is only used once every primary is down
+        drop(primaries.remove(0));
+        svc.backend_type.backends().run_health_check(false).await;
+        for _ in 0..4 {
+            assert_eq!(selected(), backup_addr);
+        }
+        assert!(svc.failover.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_no_capacity() {
+        let config = r#"
+name: test
+type: http
+algorithm: round_robin
+endpoints:
+  - ip: 127.0.0.1
+    port: 3000
+upstream:
+  max_connections: 1
+"#;
+        let service: Service = serde_yml::from_str(config).unwrap();
+        let svc = HttpService {
+            name: service.name.clone(),
+            backend_type: load_backend(&service, &service.endpoints).await.unwrap(),
+            discovery: None,
+            hash_key: None,
+            sticky: None,
+            retry: None,
+            fallback_service: None,
+            failover: Arc::new(AtomicBool::new(false)),
+            health_check_interval: None,
+            health_checked: Arc::new(Mutex::new(None)),
+            slow_start: None,
+            circuit_breaker: None,
+        };
+        let (b, _) = selection("", &svc).unwrap();
+        let stats = BackendStats::get(&b).unwrap();
+        stats.start();
+        // busy is reported apart from a missing backend, the client gets a 503
+        assert!(matches!(
+            selection("", &svc),
+            Err(Errors::ServiceUnavailable(_))
+        ));
+        stats.finish();
+        assert!(selection("", &svc).is_ok());
+    }
+
+    #[test]
+    fn test_effective_weight() {
+        // weight 1 right after the backend came up
+        assert_eq!(effective_weight(1, 0.1), 1.0);
+        assert_eq!(effective_weight(0, 0.1), 1.0);
+        assert_eq!(effective_weight(10, 0.1), 1.0);
+        assert_eq!(effective_weight(10, 0.5), 5.0);
+        let weights = [effective_weight(1, 0.1), effective_weight(1, 1.0)];
+        assert_eq!(weighted_index(&weights, 0.0), Some(0));
+        assert_eq!(weighted_index(&weights, 0.99), Some(1));
+    }
+
+    #[test]
+    fn test_weighted_index() {
+        // a backend ramping up at a tenth of weight 10 next to one of weight 3
+        let weights = [1.0, 3.0];
+        assert_eq!(weighted_index(&weights, 0.0), Some(0));
+        assert_eq!(weighted_index(&weights, 0.24), Some(0));
+        assert_eq!(weighted_index(&weights, 0.25), Some(1));
+        assert_eq!(weighted_index(&weights, 0.99), Some(1));
+        // skips empty weights, even past the end
+        assert_eq!(weighted_index(&[2.0, 0.0], 1.0), Some(0));
+        assert_eq!(weighted_index(&[0.0, 0.0], 0.5), None);
     }
-    .ok_or_else(|| Errors::ConfigError("No backend found".to_string()))
 }
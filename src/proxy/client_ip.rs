@@ -0,0 +1,116 @@
+use crate::config::access::Cidr;
+use http::HeaderMap;
+use std::net::IpAddr;
+
+// The real client IP.
+// Headers are only trusted when the peer is a trusted proxy, then the first source wins:
+// - `X-Forwarded-For`, the right-most address that is not a trusted proxy
+// - `X-Real-IP`
+// - `Forwarded` (RFC 7239), the right-most `for=` that is not a trusted proxy
+pub fn resolve(peer: IpAddr, headers: &HeaderMap, trusted: &[Cidr]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|c| c.contains(ip));
+    if !is_trusted(&peer) {
+        return peer;
+    }
+    let values = |name: &str| -> Vec<String> {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|v| v.trim().to_string())
+            .collect()
+    };
+    // the chain is read right to left, the last hop is the closest one
+    let right_most = |chain: Vec<IpAddr>| -> Option<IpAddr> {
+        chain
+            .iter()
+            .rev()
+            .find(|ip| !is_trusted(ip))
+            .or(chain.first())
+            .copied()
+    };
+    let xff: Vec<IpAddr> = values("x-forwarded-for")
+        .iter()
+        .filter_map(|v| parse_ip(v))
+        .collect();
+    if let Some(ip) = right_most(xff) {
+        return ip;
+    }
+    if let Some(ip) = values("x-real-ip").first().and_then(|v| parse_ip(v)) {
+        return ip;
+    }
+    let forwarded: Vec<IpAddr> = values("forwarded")
+        .iter()
+        .filter_map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(k, _)| k.eq_ignore_ascii_case("for"))
+                .and_then(|(_, v)| parse_ip(v))
+        })
+        .collect();
+    right_most(forwarded).unwrap_or(peer)
+}
+
+// accepts `1.2.3.4`, `1.2.3.4:80`, `::1`, `[::1]:80` and quoted values
+pub fn parse_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Some(rest) = value.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    value.rsplit_once(':')?.0.parse::<IpAddr>().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_resolve() {
+        let trusted = vec!["10.0.0.0/8".parse::<Cidr>().unwrap()];
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "1.1.1.1, 2.2.2.2, 10.0.0.2".parse().unwrap(),
+        );
+        // untrusted peers can't spoof the client IP
+        assert_eq!(resolve(ip("3.3.3.3"), &headers, &trusted), ip("3.3.3.3"));
+        assert_eq!(resolve(ip("10.0.0.1"), &headers, &trusted), ip("2.2.2.2"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-real-ip", "4.4.4.4".parse().unwrap());
+        assert_eq!(resolve(ip("10.0.0.1"), &headers, &trusted), ip("4.4.4.4"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "forwarded",
+            "for=\"[2001:db8::1]:443\";proto=https, for=10.0.0.3"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &headers, &trusted),
+            ip("2001:db8::1")
+        );
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &HeaderMap::new(), &trusted),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn test_parse_ip() {
+        assert_eq!(parse_ip("1.2.3.4:80"), Some(ip("1.2.3.4")));
+        assert_eq!(parse_ip("\"[::1]:80\""), Some(ip("::1")));
+        assert_eq!(parse_ip("::1"), Some(ip("::1")));
+        assert_eq!(parse_ip("unknown"), None);
+    }
+}
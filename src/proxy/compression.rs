@@ -0,0 +1,105 @@
+use crate::config::proxy::Compression;
+use pingora::{
+    http::ResponseHeader, modules::http::compression::ResponseCompression,
+    protocols::http::compression::Algorithm, proxy::Session,
+};
+
+static DEFAULT_LEVEL: u32 = 6;
+static DEFAULT_MIN_SIZE: usize = 1024;
+static ALGORITHMS: [&str; 3] = ["gzip", "br", "zstd"];
+static DEFAULT_CONTENT_TYPES: [&str; 6] = [
+    "text/*",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/wasm",
+    "image/svg+xml",
+];
+
+fn algorithm(name: &str) -> Option<Algorithm> {
+    match name {
+        "gzip" => Some(Algorithm::Gzip),
+        "br" => Some(Algorithm::Brotli),
+        "zstd" => Some(Algorithm::Zstd),
+        _ => None,
+    }
+}
+
+// enable the downstream compression module for the request, it picks the encoding
+// from `Accept-Encoding` and skips responses that are already encoded
+pub fn enable(session: &mut Session, compression: &Compression) {
+    let Some(ctx) = session
+        .downstream_modules_ctx
+        .get_mut::<ResponseCompression>()
+    else {
+        return;
+    };
+    let level = compression.level.unwrap_or(DEFAULT_LEVEL);
+    let enabled: Vec<&str> = match &compression.algorithms {
+        Some(algorithms) => algorithms.iter().map(|a| a.as_str()).collect(),
+        None => ALGORITHMS.to_vec(),
+    };
+    for name in ALGORITHMS {
+        if let Some(algorithm) = algorithm(name) {
+            let level = if enabled.contains(&name) { level } else { 0 };
+            ctx.adjust_algorithm_level(algorithm, level);
+        }
+    }
+    // upstream encodings the client doesn't accept are decoded
+    ctx.adjust_decompression(compression.decompress.unwrap_or(true));
+}
+
+// skip compression of small responses and content types that aren't allowed
+pub fn response_filter(
+    session: &mut Session,
+    compression: &Compression,
+    response: &ResponseHeader,
+) {
+    let header = |name: &str| response.headers.get(name).and_then(|v| v.to_str().ok());
+    let too_small = header("content-length")
+        .and_then(|v| v.parse::<usize>().ok())
+        .is_some_and(|len| len < compression.min_size.unwrap_or(DEFAULT_MIN_SIZE));
+    let content_type = header("content-type")
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    let allowed = match &compression.content_types {
+        Some(types) => types.iter().any(|t| content_type_matches(t, &content_type)),
+        None => DEFAULT_CONTENT_TYPES
+            .iter()
+            .any(|t| content_type_matches(t, &content_type)),
+    };
+    if too_small || !allowed {
+        if let Some(ctx) = session
+            .downstream_modules_ctx
+            .get_mut::<ResponseCompression>()
+        {
+            ctx.adjust_level(0);
+        }
+    }
+}
+
+fn content_type_matches(pattern: &str, content_type: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(prefix) => content_type
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.starts_with('/')),
+        None => pattern.eq_ignore_ascii_case(content_type),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_type_matches() {
+        assert!(content_type_matches("text/*", "text/html"));
+        assert!(!content_type_matches("text/*", "textual/html"));
+        assert!(content_type_matches("application/json", "application/json"));
+        assert!(!content_type_matches(
+            "application/json",
+            "application/jsonp"
+        ));
+    }
+}
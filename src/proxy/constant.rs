@@ -1,2 +1,12 @@
 // static
 pub static WELL_KNOWN_PAHT_PREFIX: &str = "/.well-known/acme-challenge/";
+// replaced by the CORS policy of a route
+pub static CORS_RESPONSE_HEADERS: [&str; 5] = [
+    "access-control-allow-origin",
+    "access-control-allow-credentials",
+    "access-control-allow-methods",
+    "access-control-allow-headers",
+    "access-control-expose-headers",
+];
+// seconds a client waits before retrying when every endpoint is busy
+pub static RETRY_AFTER_SECS: &str = "1";
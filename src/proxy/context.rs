@@ -1,9 +1,38 @@
-use pingora::lb::Backend;
-use std::collections::HashMap;
+use crate::config::{
+    circuit_breaker::Permit,
+    cors::CorsPolicy,
+    selection::BackendStats,
+    store::{HttpService, Route},
+};
+use pingora::{lb::Backend, protocols::l4::socket::SocketAddr};
+use std::{collections::HashMap, net, sync::Arc, time::Instant};
 
 pub struct Context {
     pub backend: Backend,
     pub variables: HashMap<String, String>,
+    // stats of the backend the request is in flight on, and when it was sent
+    pub upstream: Option<(Arc<BackendStats>, Instant)>,
+    // `Set-Cookie` value that pins the client to the selected backend
+    pub sticky_cookie: Option<String>,
+    // selected http service and key, used to select another backend on retry
+    pub service: Option<&'static HttpService>,
+    // matched route, its response options are applied in `response_filter`
+    pub route: Option<&'static Route>,
+    pub selection_key: String,
+    // upstream attempts and the backends they went to
+    pub tries: u32,
+    pub tried: Vec<SocketAddr>,
+    // admission by the circuit breaker, the outcome is recorded in `logging`,
+    // the permit is released on any other path when the context is dropped
+    pub circuit_breaker: Option<Permit<'static>>,
+    // client and listener address of the downstream connection, sent in PROXY headers
+    pub connection: Option<(net::SocketAddr, net::SocketAddr)>,
+    // the upstream connection must not be reused
+    pub close_upstream: bool,
+    // CORS policy of the route and the request origin
+    pub cors: Option<(&'static CorsPolicy, String)>,
+    // primary cache key when the route caches the request
+    pub cache_key: Option<String>,
 }
 
 impl Context {
@@ -11,6 +40,40 @@ impl Context {
         Self {
             backend: Backend::new("127.0.0.1:80").expect("Unable to create backend"),
             variables: HashMap::new(),
+            upstream: None,
+            sticky_cookie: None,
+            service: None,
+            route: None,
+            selection_key: String::new(),
+            tries: 0,
+            tried: Vec::new(),
+            circuit_breaker: None,
+            connection: None,
+            close_upstream: false,
+            cors: None,
+            cache_key: None,
+        }
+    }
+
+    // mark the selected backend as in flight until `finish_upstream`
+    pub fn start_upstream(&mut self) {
+        self.finish_upstream();
+        if let Some(stats) = BackendStats::get(&self.backend) {
+            stats.start();
+            self.upstream = Some((stats.clone(), Instant::now()));
+        }
+    }
+
+    // record the upstream latency for latency aware algorithms
+    pub fn observe_upstream(&self) {
+        if let Some((stats, started)) = &self.upstream {
+            stats.observe(started.elapsed());
+        }
+    }
+
+    pub fn finish_upstream(&mut self) {
+        if let Some((stats, _)) = self.upstream.take() {
+            stats.finish();
         }
     }
 }
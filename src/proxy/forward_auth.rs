@@ -0,0 +1,332 @@
+use super::backend;
+use crate::config::{proxy::ForwardAuth, store::HttpService};
+use crate::errors::Errors;
+use bytes::Bytes;
+use pingora::{http::RequestHeader, prelude::HttpPeer};
+use reqwest::header::HeaderMap;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+// connect timeout, read timeout, idle timeout and tcp keepalive of the peer
+type ClientOptions = (
+    Option<Duration>,
+    Option<Duration>,
+    Option<Duration>,
+    Option<Duration>,
+);
+
+// one client per distinct peer options, so each keeps its connection pool
+static CLIENTS: LazyLock<Mutex<HashMap<ClientOptions, reqwest::Client>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+static DEFAULT_TIMEOUT_MS: u64 = 5000;
+// sent to the auth service when `request_headers` isn't set
+static DEFAULT_REQUEST_HEADERS: [&str; 2] = ["authorization", "cookie"];
+// headers of a denied response that are passed on to the client
+static DENY_HEADERS: [&str; 4] = ["www-authenticate", "location", "set-cookie", "content-type"];
+
+pub enum Outcome {
+    // headers to copy onto the upstream request
+    Allow(Vec<(String, String)>),
+    Deny {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: Bytes,
+    },
+}
+
+// client with the connection options of the auth service endpoint
+fn client(peer: Option<&HttpPeer>) -> Result<reqwest::Client, Errors> {
+    let options = peer.map(|p| &p.options);
+    let key = (
+        options.and_then(|o| o.total_connection_timeout.or(o.connection_timeout)),
+        options.and_then(|o| o.read_timeout),
+        options.and_then(|o| o.idle_timeout),
+        options.and_then(|o| o.tcp_keepalive.as_ref().map(|k| k.idle)),
+    );
+    let mut clients = match CLIENTS.lock() {
+        Ok(clients) => clients,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    if let Some(client) = clients.get(&key) {
+        return Ok(client.clone());
+    }
+    let (connect, read, idle, keepalive) = key;
+    let mut builder = reqwest::Client::builder().tcp_keepalive(keepalive);
+    if let Some(timeout) = connect {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(timeout) = read {
+        builder = builder.read_timeout(timeout);
+    }
+    if let Some(timeout) = idle {
+        builder = builder.pool_idle_timeout(timeout);
+    }
+    let client = builder
+        .build()
+        .map_err(|e| Errors::ProxyError(format!("Unable to create the auth client: {}", e)))?;
+    clients.insert(key, client.clone());
+    Ok(client)
+}
+
+// Ask the auth service whether the request may pass.
+// The subrequest has the original method, the original URI in `X-Forwarded-Uri`
+// and the selected request headers. The connect and read timeouts of the
+// service's `upstream` apply, `timeout_ms` bounds the whole subrequest.
+pub async fn check(
+    req: &RequestHeader,
+    forward_auth: &ForwardAuth,
+    service: &HttpService,
+    client_ip: IpAddr,
+    host: &str,
+    is_tls: bool,
+) -> Result<Outcome, Errors> {
+    let (backend, _) = backend::selection(&client_ip.to_string(), service)?;
+    let Some(addr) = backend.addr.as_inet() else {
+        return Err(Errors::ProxyError(format!(
+            "Forward auth service {} must use tcp endpoints",
+            service.name
+        )));
+    };
+    let method = reqwest::Method::from_bytes(req.method.as_str().as_bytes())
+        .map_err(|e| Errors::ProxyError(e.to_string()))?;
+    let uri = req.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let path = forward_auth.path.as_deref().unwrap_or("/");
+    let timeout = forward_auth.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS);
+
+    let mut request = client(backend.ext.get::<HttpPeer>())?
+        .request(method, format!("http://{}{}", addr, path))
+        .timeout(Duration::from_millis(timeout))
+        .header("x-forwarded-method", req.method.as_str())
+        .header("x-forwarded-uri", uri)
+        .header("x-forwarded-host", host)
+        .header("x-forwarded-proto", if is_tls { "https" } else { "http" })
+        .header("x-forwarded-for", client_ip.to_string());
+    let names: Vec<&str> = match &forward_auth.request_headers {
+        Some(names) => names.iter().map(|n| n.as_str()).collect(),
+        None => DEFAULT_REQUEST_HEADERS.to_vec(),
+    };
+    for name in names {
+        for value in req.headers.get_all(name) {
+            request = request.header(name, value.as_bytes());
+        }
+    }
+
+    let response = request.send().await.map_err(|e| {
+        Errors::ProxyError(format!(
+            "Forward auth request to {} failed: {}",
+            service.name, e
+        ))
+    })?;
+    let status = response.status();
+    if status.is_success() {
+        let names = forward_auth
+            .copy_headers
+            .iter()
+            .flatten()
+            .map(|n| n.as_str());
+        let headers = copy_headers(response.headers(), names);
+        return Ok(Outcome::Allow(headers));
+    }
+    let headers = copy_headers(response.headers(), DENY_HEADERS.into_iter());
+    let body = response.bytes().await.unwrap_or_default();
+    Ok(Outcome::Deny {
+        status: status.as_u16(),
+        headers,
+        body,
+    })
+}
+
+fn copy_headers<'a>(
+    headers: &HeaderMap,
+    names: impl Iterator<Item = &'a str>,
+) -> Vec<(String, String)> {
+    names
+        .flat_map(|name| {
+            headers
+                .get_all(name)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .map(move |v| (name.to_string(), v.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{backend::load_backend, proxy::Service};
+    use std::sync::{atomic::AtomicBool, Arc};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::oneshot,
+    };
+
+    // an auth service answering one request with `response`, the request it received is sent back
+    async fn auth_service(response: &'static str) -> (u16, oneshot::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            let _ = tx.send(String::from_utf8_lossy(&request).to_lowercase());
+            if !response.is_empty() {
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+            // keep the connection open until the client is done with it
+            let _ = stream.read(&mut buf).await;
+        });
+        (port, rx)
+    }
+
+    async fn http_service(port: u16, upstream: &str) -> HttpService {
+        let config = format!(
+            r#"
+name: auth
+type: http
+algorithm: round_robin
+endpoints:
+  - ip: 127.0.0.1
+    port: {}
+{}
+"#,
+            port, upstream
+        );
+        let service: Service = serde_yml::from_str(&config).unwrap();
+        HttpService {
+            name: service.name.clone(),
+            backend_type: load_backend(&service, &service.endpoints).await.unwrap(),
+            discovery: None,
+            hash_key: None,
+            sticky: None,
+            retry: None,
+            fallback_service: None,
+            failover: Arc::new(AtomicBool::new(false)),
+            health_check_interval: None,
+            health_checked: Arc::new(std::sync::Mutex::new(None)),
+            slow_start: None,
+            circuit_breaker: None,
+        }
+    }
+
+    fn request() -> RequestHeader {
+        let mut req = RequestHeader::build("POST", b"/orders?id=1", None).unwrap();
+        req.insert_header("Authorization", "Bearer token").unwrap();
+        req.insert_header("Cookie", "session=1").unwrap();
+        req.insert_header("X-Other", "1").unwrap();
+        req
+    }
+
+    fn forward_auth() -> ForwardAuth {
+        ForwardAuth {
+            service: "auth".to_string(),
+            path: Some("/verify".to_string()),
+            copy_headers: Some(vec!["x-user".to_string()]),
+            ..Default::default()
+        }
+    }
+
+    async fn run(service: &HttpService) -> Result<Outcome, Errors> {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        check(
+            &request(),
+            &forward_auth(),
+            service,
+            ip,
+            "example.com",
+            true,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_allow() {
+        let (port, received) = auth_service(
+            "HTTP/1.1 200 OK\r\nX-User: alice\r\nX-Internal: 1\r\nContent-Length: 0\r\n\r\n",
+        )
+        .await;
+        let service = http_service(port, "").await;
+        let Ok(Outcome::Allow(headers)) = run(&service).await else {
+            panic!("request should be allowed");
+        };
+        // only the configured headers are copied
+        assert_eq!(headers, vec![("x-user".to_string(), "alice".to_string())]);
+
+        let received = received.await.unwrap();
+        assert!(received.starts_with("post /verify http/1.1"));
+        for header in [
+            "x-forwarded-method: post",
+            "x-forwarded-uri: /orders?id=1",
+            "x-forwarded-host: example.com",
+            "x-forwarded-proto: https",
+            "x-forwarded-for: 10.0.0.1",
+            "authorization: bearer token",
+            "cookie: session=1",
+        ] {
+            assert!(received.contains(header), "{} missing", header);
+        }
+        assert!(!received.contains("x-other"));
+    }
+
+    #[tokio::test]
+    async fn test_deny() {
+        let (port, _received) = auth_service(
+            "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Basic realm=\"app\"\r\nX-User: alice\r\nContent-Type: text/plain\r\nContent-Length: 6\r\n\r\ndenied",
+        )
+        .await;
+        let service = http_service(port, "").await;
+        let Ok(Outcome::Deny {
+            status,
+            headers,
+            body,
+        }) = run(&service).await
+        else {
+            panic!("request should be denied");
+        };
+        assert_eq!(status, 401);
+        assert_eq!(&body[..], b"denied");
+        // the challenge is passed on, the headers meant for the upstream are not
+        assert_eq!(
+            headers,
+            vec![
+                (
+                    "www-authenticate".to_string(),
+                    "Basic realm=\"app\"".to_string()
+                ),
+                ("content-type".to_string(), "text/plain".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unavailable() {
+        // nothing listens on the port, the proxy answers with a 500
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let service = http_service(port, "").await;
+        assert!(matches!(run(&service).await, Err(Errors::ProxyError(_))));
+
+        // a hanging auth service fails after the read timeout of the service
+        let (port, _received) = auth_service("").await;
+        let service = http_service(port, "upstream:\n  read_timeout_ms: 100").await;
+        let started = std::time::Instant::now();
+        assert!(matches!(run(&service).await, Err(Errors::ProxyError(_))));
+        assert!(started.elapsed() < Duration::from_millis(DEFAULT_TIMEOUT_MS));
+    }
+}
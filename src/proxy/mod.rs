@@ -1,36 +1,57 @@
+mod actions;
 mod backend;
+mod client_ip;
+mod compression;
 mod constant;
 mod context;
 mod dynamic_certificate;
+mod forward_auth;
+mod proxy_protocol;
 mod request_modifiers;
 mod response;
+mod response_modifiers;
+mod retry;
+mod static_files;
+mod sticky;
+mod variables;
 
 use crate::{
-    config::{self, store},
+    config::{
+        self,
+        access::Cidr,
+        backend::{apply_per_try_timeout, SendProxyProtocol},
+        jwt, store,
+    },
     errors::Errors,
 };
 use async_trait::async_trait;
-use constant::WELL_KNOWN_PAHT_PREFIX;
+use constant::{RETRY_AFTER_SECS, WELL_KNOWN_PAHT_PREFIX};
 use context::Context;
 use dynamic_certificate::DynamicCertificate;
 use http::Version;
 use pingora::{
-    http::ResponseHeader,
+    cache::{key::HashBinary, CacheKey, CacheMeta, NoCacheReason, RespCacheable},
+    http::{RequestHeader, ResponseHeader},
     listeners::tls::TlsSettings,
     prelude::{background_service, HttpPeer, Opt},
     proxy::{self, ProxyHttp, Session},
     server::{configuration::ServerConf, Server, ShutdownWatch},
     services::background::BackgroundService,
-    ErrorType,
+    ErrorSource, ErrorType,
 };
 use serde_json::json;
-use std::time::Duration;
+use std::{
+    net,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 use tokio::time::interval;
 
 pub struct ProxyBackgroundService;
 #[async_trait]
 impl BackgroundService for ProxyBackgroundService {
     async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut period_1s = interval(Duration::from_secs(1));
         let mut period_10s = interval(Duration::from_secs(10));
         let mut period_1d = interval(Duration::from_secs(86400));
         let mut period_1d_is_first_run = true;
@@ -41,6 +62,14 @@ impl BackgroundService for ProxyBackgroundService {
                     tracing::info!("Shutting down background service");
                     break;
                 }
+                _ = period_1s.tick() => {
+                    // service discovery
+                    store::update_discovery().await;
+                    // health checks
+                    store::run_health_checks().await;
+                    // access list files
+                    store::reload_access_lists();
+                }
                 _ = period_10s.tick() => {
                     // acme request queue
                     store::acme_request_queue().await;
@@ -125,10 +154,23 @@ impl EasyProxy {
         pingora_server.configuration = conf.into();
 
         // proxy service
-        let mut pingora_svc =
-            proxy::http_proxy_service(&pingora_server.configuration, easy_proxy.clone());
-        pingora_svc.add_tcp(&app_conf.proxy.http);
-        pingora_server.add_service(pingora_svc);
+        match proxy_protocol_trusted("http")? {
+            Some(trusted) => {
+                let app = proxy::http_proxy(&pingora_server.configuration, easy_proxy.clone());
+                pingora_server.add_service(proxy_protocol::Listener::new(
+                    "Pingora HTTP Proxy Service (PROXY protocol)",
+                    &app_conf.proxy.http,
+                    trusted,
+                    app,
+                ));
+            }
+            None => {
+                let mut pingora_svc =
+                    proxy::http_proxy_service(&pingora_server.configuration, easy_proxy.clone());
+                pingora_svc.add_tcp(&app_conf.proxy.http);
+                pingora_server.add_service(pingora_svc);
+            }
+        }
 
         // background service
         let background_service = background_service("proxy", ProxyBackgroundService {});
@@ -136,17 +178,35 @@ impl EasyProxy {
 
         tracing::info!("Proxy server started on http://{}", app_conf.proxy.http);
         if let Some(https) = &app_conf.proxy.https {
-            let mut pingora_svc =
-                proxy::http_proxy_service(&pingora_server.configuration, easy_proxy.clone());
-            let mut tls = match TlsSettings::with_callbacks(Box::new(DynamicCertificate::new())) {
-                Ok(tls) => tls,
-                Err(e) => {
-                    return Err(Errors::PingoraError(format!("{}", e)));
+            match proxy_protocol_trusted("https")? {
+                Some(trusted) => {
+                    let app = proxy::http_proxy(&pingora_server.configuration, easy_proxy.clone());
+                    let listener = proxy_protocol::Listener::new(
+                        "Pingora HTTPS Proxy Service (PROXY protocol)",
+                        https,
+                        trusted,
+                        app,
+                    )
+                    .tls(Box::new(DynamicCertificate::new()))?;
+                    pingora_server.add_service(listener);
                 }
-            };
-            tls.enable_h2();
-            pingora_svc.add_tls_with_settings(https, None, tls);
-            pingora_server.add_service(pingora_svc);
+                None => {
+                    let mut pingora_svc = proxy::http_proxy_service(
+                        &pingora_server.configuration,
+                        easy_proxy.clone(),
+                    );
+                    let mut tls =
+                        match TlsSettings::with_callbacks(Box::new(DynamicCertificate::new())) {
+                            Ok(tls) => tls,
+                            Err(e) => {
+                                return Err(Errors::PingoraError(format!("{}", e)));
+                            }
+                        };
+                    tls.enable_h2();
+                    pingora_svc.add_tls_with_settings(https, None, tls);
+                    pingora_server.add_service(pingora_svc);
+                }
+            }
             tracing::info!("Proxy server started on https://{}", https);
         }
 
@@ -332,9 +392,37 @@ impl ProxyHttp for EasyProxy {
                     .await;
             }
         };
-        let ip = match res.session.client_addr() {
+        // plain http requests of tls routes are redirected
+        let route = matched.value;
+        ctx.route = Some(route);
+        if let Some(tls) = &route.tls {
+            // println!("TLS: {:?}", session.digest().unwrap().ssl_digest.clone().unwrap());
+            let is_tls = match res.session.digest() {
+                Some(d) => d.ssl_digest.is_some(),
+                None => false,
+            };
+            // println!("TLS: {}", is_tls);
+            if tls.redirect.unwrap_or(false) && !is_tls {
+                // println!("Redirecting to https");
+                if tls_port != "443" {
+                    res.redirect_https(host, path, Some(tls_port.to_string()));
+                } else {
+                    res.redirect_https(host, path, None);
+                }
+                return res.send().await;
+            }
+        }
+
+        // with PROXY protocol the peer is the source from the header
+        let connection = res
+            .session
+            .client_addr()
+            .and_then(|c| c.as_inet())
+            .zip(res.session.server_addr().and_then(|s| s.as_inet()))
+            .map(|(client, server)| (*client, *server));
+        let peer_ip = match res.session.client_addr() {
             Some(ip) => match ip.as_inet() {
-                Some(ip) => ip.ip().to_string(),
+                Some(ip) => ip.ip(),
                 None => {
                     return res
                         .status(400)
@@ -357,87 +445,239 @@ impl ProxyHttp for EasyProxy {
                     .await;
             }
         };
+        // the real client IP, forwarding headers are only read from trusted proxies
+        let client_ip = client_ip::resolve(
+            peer_ip,
+            &res.session.req_header().headers,
+            config::runtime::trusted_proxies(),
+        );
+        ctx.variables
+            .insert("CLIENT_IP".to_string(), client_ip.to_string());
+        // the port of the resolved client is only known when it is the peer
+        ctx.connection = connection.map(|(client, server)| {
+            let port = if client.ip() == client_ip {
+                client.port()
+            } else {
+                0
+            };
+            (net::SocketAddr::new(client_ip, port), server)
+        });
+        ctx.variables.insert("HOST".to_string(), host.clone());
+        ctx.variables.insert("PATH".to_string(), path.clone());
 
-        ctx.variables.insert("CLIENT_IP".to_string(), ip.clone());
-        // x-real-ip
-        let selection_ip = match res.session.get_header("x-real-ip") {
-            Some(h) => match h.to_str() {
-                Ok(h) => format!("{}-{}", ip, h),
-                Err(e) => {
+        // CORS policy of the route, also applied to the rejections below
+        if let Some(cors) = matched.value.cors.as_deref() {
+            if let Some(origin) = res
+                .session
+                .get_header("origin")
+                .and_then(|h| h.to_str().ok())
+            {
+                ctx.cors = Some((cors, origin.to_string()));
+            }
+        }
+
+        // access control, global first
+        for access in [&store_conf.access, &matched.value.access]
+            .into_iter()
+            .flatten()
+        {
+            if !access.is_allowed(&client_ip) {
+                return res
+                    .status(access.status)
+                    .body_json(json!({
+                        "error": "ACCESS_DENIED",
+                        "message": access.message,
+                    }))?
+                    .route_headers(ctx)?
+                    .send()
+                    .await;
+            }
+        }
+
+        // rate limit before anything else is done for the request
+        if let Some(limiter) = &matched.value.rate_limit {
+            let key = variables::expand(&limiter.key, res.session, ctx);
+            let decision = limiter.check(&key);
+            if !decision.allowed {
+                return res
+                    .status(limiter.status)
+                    .header("Retry-After", &decision.reset.to_string())
+                    .header("RateLimit-Limit", &decision.limit.to_string())
+                    .header("RateLimit-Remaining", &decision.remaining.to_string())
+                    .header("RateLimit-Reset", &decision.reset.to_string())
+                    .body_json(json!({
+                        "error": "RATE_LIMITED",
+                        "message": limiter.message,
+                    }))?
+                    .route_headers(ctx)?
+                    .send()
+                    .await;
+            }
+        }
+
+        // CORS, preflight requests are answered before authentication
+        if let Some((cors, origin)) = &ctx.cors {
+            let header = |name: &str| {
+                res.session
+                    .get_header(name)
+                    .and_then(|h| h.to_str().ok())
+                    .map(|h| h.to_string())
+            };
+            let preflight = match header("access-control-request-method") {
+                Some(method) if res.session.req_header().method == "OPTIONS" => Some(method),
+                _ => None,
+            };
+            if let Some(method) = preflight {
+                let requested = header("access-control-request-headers");
+                let headers = cors
+                    .preflight(origin, &method, requested.as_deref())
+                    .unwrap_or_default();
+                res.status(204);
+                for (name, value) in headers.iter() {
+                    res.header(name, value);
+                }
+                return res.header("Content-Length", "0").send().await;
+            }
+        }
+
+        // basic authentication
+        if let Some(auth) = &matched.value.basic_auth {
+            let user = res
+                .session
+                .get_header("authorization")
+                .and_then(|h| h.to_str().ok())
+                .and_then(|h| auth.authenticate(h));
+            match user {
+                Some(user) => {
+                    ctx.variables.insert("AUTH_USER".to_string(), user);
+                    if auth.strip_authorization {
+                        let _ = res.session.req_header_mut().remove_header("authorization");
+                    }
+                }
+                None => {
                     return res
-                        .status(400)
+                        .status(401)
+                        .header("WWW-Authenticate", &auth.challenge())
                         .body_json(json!({
-                            "error": "PARSE_ERROR",
-                            "message": e.to_string(),
+                            "error": "UNAUTHORIZED",
+                            "message": "Authentication required",
                         }))?
+                        .route_headers(ctx)?
                         .send()
                         .await;
                 }
-            },
-            None => ip,
-        };
-        // x-forwarded-for
-        let selection_ip = match res.session.get_header("x-forwarded-for") {
-            Some(h) => match h.to_str() {
-                Ok(h) => format!("{}-{}", selection_ip, h),
-                Err(e) => {
+            }
+        }
+
+        // JWT validation
+        if let Some(jwt) = &matched.value.jwt {
+            let token = res
+                .session
+                .get_header("authorization")
+                .and_then(|h| h.to_str().ok())
+                .and_then(jwt::bearer_token)
+                .map(|t| t.to_string());
+            let result = match &token {
+                Some(token) => jwt.validate(token).map_err(Some),
+                None => Err(None),
+            };
+            match result {
+                Ok(claims) => {
+                    for name in jwt.claims.iter() {
+                        if let Some(value) = claims.get(name) {
+                            ctx.variables
+                                .insert(format!("JWT_{}", name), jwt::claim_value(value));
+                        }
+                    }
+                }
+                Err(error) => {
                     return res
-                        .status(400)
+                        .status(401)
+                        .header("WWW-Authenticate", &jwt.challenge(error.as_deref()))
                         .body_json(json!({
-                            "error": "PARSE_ERROR",
-                            "message": e.to_string(),
+                            "error": "UNAUTHORIZED",
+                            "message": error.unwrap_or_else(|| "Bearer token required".to_string()),
                         }))?
+                        .route_headers(ctx)?
                         .send()
                         .await;
                 }
-            },
-            None => selection_ip,
-        };
-        // prepare the selection key
-        let service_ref = &matched.value.service;
-        let selection_key = format!("{}:{}", selection_ip, path);
+            }
+        }
 
-        // modify the request
-        let route = matched.value;
-        if let Some(tls) = &route.tls {
-            // println!("TLS: {:?}", session.digest().unwrap().ssl_digest.clone().unwrap());
-            let is_tls = match res.session.digest() {
-                Some(d) => d.ssl_digest.is_some(),
-                None => false,
+        // external authorization
+        if let Some(fa) = &matched.value.forward_auth {
+            let outcome = match store_conf.http_services.get(&fa.service) {
+                Some(auth_service) => {
+                    let is_tls = is_tls(res.session);
+                    forward_auth::check(
+                        res.session.req_header(),
+                        fa,
+                        auth_service,
+                        client_ip,
+                        &host,
+                        is_tls,
+                    )
+                    .await
+                }
+                None => Err(Errors::ConfigError(format!(
+                    "Forward auth service {} not found",
+                    fa.service
+                ))),
             };
-            // println!("TLS: {}", is_tls);
-            if tls.redirect.unwrap_or(false) && !is_tls {
-                // println!("Redirecting to https");
-                if tls_port != "443" {
-                    res.redirect_https(host, path, Some(tls_port.to_string()));
-                } else {
-                    res.redirect_https(host, path, None);
+            match outcome {
+                Ok(forward_auth::Outcome::Allow(headers)) => {
+                    // never trust the client's own copy of these headers
+                    for name in fa.copy_headers.iter().flatten() {
+                        let _ = res.session.req_header_mut().remove_header(name.as_str());
+                    }
+                    for (name, value) in headers {
+                        let _ = res.session.req_header_mut().append_header(name, value);
+                    }
+                }
+                Ok(forward_auth::Outcome::Deny {
+                    status,
+                    headers,
+                    body,
+                }) => {
+                    res.status(status);
+                    for (name, value) in headers.iter() {
+                        res.header(name, value);
+                    }
+                    return res.body(body).route_headers(ctx)?.send().await;
+                }
+                Err(e) => {
+                    tracing::error!("{}", e);
+                    return res
+                        .status(500)
+                        .body_json(json!({
+                            "error": "AUTH_ERROR",
+                            "message": "Unable to authorize the request",
+                        }))?
+                        .route_headers(ctx)?
+                        .send()
+                        .await;
                 }
-                return res.send().await;
             }
         }
-        match request_modifiers::rewrite(res.session, &route.path.path, &service_ref.rewrite).await
-        {
-            Ok(_) => {}
-            Err(e) => {
-                return res
-                    .status(500)
-                    .body_json(json!({
-                        "error": "MODIFY_ERROR",
-                        "message": e.to_string(),
-                    }))?
-                    .send()
-                    .await;
-            }
+
+        // paths that return a response or redirect
+        if let Some(action) = &route.action {
+            return actions::run(&mut res, action, ctx).await;
         }
-        request_modifiers::headers(
-            res.session,
-            ctx,
-            route.add_headers.as_ref().unwrap_or(&vec![]),
-            route.remove_headers.as_ref().unwrap_or(&vec![]),
-        );
 
-        // select the backend for http service
+        // static services are served by the proxy
+        let service_ref = &route.service;
+        if let Some(files) = store_conf.static_services.get(&service_ref.name) {
+            let uri_path = res.session.req_header().uri.path().to_string();
+            let file_path = match &service_ref.rewrite {
+                Some(rewrite) => uri_path.replace(&route.path.path, rewrite),
+                None => uri_path,
+            };
+            return static_files::serve(res.session, ctx, files, &file_path).await;
+        }
+
+        // get the http service
         let service = match store_conf.http_services.get(&service_ref.name) {
             Some(s) => s,
             None => {
@@ -451,18 +691,122 @@ impl ProxyHttp for EasyProxy {
                     .await;
             }
         };
-        ctx.backend = match backend::selection(&selection_key, service) {
-            Ok(b) => b,
+
+        // reject early while the circuit breaker is open
+        if let Some(breaker) = service.circuit_breaker.as_deref() {
+            match breaker.acquire() {
+                Some(permit) => ctx.circuit_breaker = Some(permit),
+                None => {
+                    return res
+                        .status(breaker.status)
+                        .body_json(json!({
+                            "error": "SERVICE_UNAVAILABLE",
+                            "message": breaker.message,
+                        }))?
+                        .send()
+                        .await;
+                }
+            }
+        }
+
+        // prepare the selection key before the request is modified
+        let selection_key = match &service.hash_key {
+            Some(hash_key) => variables::expand(hash_key, res.session, ctx),
+            None => format!("{}:{}", client_ip, path),
+        };
+        let sticky_backend = service
+            .sticky
+            .as_ref()
+            .and_then(|sticky| sticky::backend(sticky, res.session, service));
+
+        // the cache key is taken from the request before it is modified
+        if let Some(cache) = &matched.value.cache {
+            let req = res.session.req_header();
+            if req.method == http::Method::GET || req.method == http::Method::HEAD {
+                let key = match &cache.key {
+                    Some(template) => variables::expand(template, res.session, ctx),
+                    None => {
+                        let uri = req.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+                        let scheme = if is_tls(res.session) { "https" } else { "http" };
+                        config::cache::default_key(header_selector, scheme, &host, uri)
+                    }
+                };
+                ctx.cache_key = Some(key);
+            }
+        }
+
+        // modify the request
+        match request_modifiers::rewrite(res.session, &route.path.path, &service_ref.rewrite).await
+        {
+            Ok(_) => {}
             Err(e) => {
                 return res
                     .status(500)
                     .body_json(json!({
-                        "error": "CONFIG_ERROR",
+                        "error": "MODIFY_ERROR",
                         "message": e.to_string(),
                     }))?
                     .send()
                     .await;
             }
+        }
+        if let Some(fh) = &route.forwarded_headers {
+            let is_tls = is_tls(res.session);
+            request_modifiers::forwarded_headers(
+                res.session,
+                fh,
+                peer_ip,
+                client_ip,
+                &host,
+                is_tls,
+            );
+        }
+        request_modifiers::headers(
+            res.session,
+            ctx,
+            route.add_headers.as_ref().unwrap_or(&vec![]),
+            route.remove_headers.as_ref().unwrap_or(&vec![]),
+        );
+
+        // select the backend for http service
+        ctx.service = Some(service);
+        if let Some(c) = &route.compression {
+            compression::enable(res.session, c);
+        }
+        ctx.selection_key = selection_key.clone();
+        ctx.backend = match sticky_backend {
+            Some(b) => b,
+            None => match backend::selection(&selection_key, service) {
+                Ok((b, selected)) => {
+                    // pin the client to the selected backend
+                    if let Some(sticky) = &selected.sticky {
+                        ctx.sticky_cookie =
+                            Some(sticky::set_cookie(sticky, &b, is_tls(res.session)));
+                    }
+                    b
+                }
+                Err(Errors::ServiceUnavailable(message)) => {
+                    return res
+                        .status(503)
+                        .header("Retry-After", RETRY_AFTER_SECS)
+                        .body_json(json!({
+                            "error": "SERVICE_UNAVAILABLE",
+                            "message": message,
+                        }))?
+                        .send()
+                        .await;
+                }
+                Err(e) => {
+                    return res
+                        .status(500)
+                        .body_json(json!({
+                            "error": "CONFIG_ERROR",
+                            "message": e.to_string(),
+                        }))?
+                        .send()
+                        .await;
+                }
+            },
         };
         // return false to continue processing the request
         Ok(false)
@@ -470,10 +814,35 @@ impl ProxyHttp for EasyProxy {
 
     async fn upstream_peer(
         &self,
-        _session: &mut Session,
+        session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<Box<HttpPeer>> {
-        let peer = match ctx.backend.ext.get::<HttpPeer>() {
+        // retry: prefer a backend that hasn't been tried yet
+        if ctx.tries > 0 {
+            if let Some(service) = ctx.service {
+                let tried = &ctx.tried;
+                if let Ok((b, selected)) =
+                    backend::selection_with(&ctx.selection_key, service, |b, healthy| {
+                        healthy && !tried.contains(&b.addr)
+                    })
+                {
+                    tracing::info!(
+                        "Retrying service {} on backend {} (attempt {})",
+                        selected.name,
+                        b.addr,
+                        ctx.tries + 1
+                    );
+                    if let Some(sticky) = &selected.sticky {
+                        ctx.sticky_cookie = Some(sticky::set_cookie(sticky, &b, is_tls(session)));
+                    }
+                    ctx.backend = b;
+                }
+            }
+        }
+        ctx.tries += 1;
+        ctx.tried.push(ctx.backend.addr.clone());
+        let send_proxy_protocol = ctx.backend.ext.get::<SendProxyProtocol>().is_some();
+        let mut peer = match ctx.backend.ext.get::<HttpPeer>() {
             Some(p) => p.clone(),
             None => {
                 return Err(pingora::Error::because(
@@ -483,19 +852,108 @@ impl ProxyHttp for EasyProxy {
                 ));
             }
         };
+        if let Some(timeout) = ctx
+            .service
+            .and_then(|s| s.retry.as_ref())
+            .and_then(|r| r.per_try_timeout)
+        {
+            apply_per_try_timeout(&mut peer, timeout);
+        }
+        ctx.close_upstream = false;
+        if send_proxy_protocol {
+            if let Some((source, destination)) = ctx.connection {
+                peer.options.custom_l4 = Some(Arc::new(proxy_protocol::Connect {
+                    source,
+                    destination,
+                }));
+                // the header belongs to this client, so the connection can't be pooled
+                ctx.close_upstream = true;
+            }
+        }
+        ctx.start_upstream();
         Ok(Box::new(peer))
     }
 
-    async fn response_filter(
+    async fn upstream_request_filter(
         &self,
         _session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<()> {
+        if ctx.close_upstream {
+            upstream_request.insert_header("connection", "close")?;
+        }
+        Ok(())
+    }
+
+    fn fail_to_connect(
+        &self,
+        session: &mut Session,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<pingora::Error>,
+    ) -> Box<pingora::Error> {
+        if retry::should_retry(ctx, session, &e, retry::Failure::Connect) {
+            e.set_retry(true);
+        }
+        e
+    }
+
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<pingora::Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<pingora::Error> {
+        let mut e = e.more_context(format!("Peer: {}", peer));
+        // only reused client connections where retry buffer is not truncated
+        e.retry
+            .decide_reuse(client_reused && !session.as_ref().retry_buffer_truncated());
+        if !session.as_ref().retry_buffer_truncated()
+            && retry::should_retry(ctx, session, &e, retry::Failure::Proxy)
+        {
+            e.set_retry(true);
+        }
+        e
+    }
+
+    async fn response_filter(
+        &self,
+        session: &mut Session,
         upstream_response: &mut ResponseHeader,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
     ) -> pingora::Result<()> {
+        ctx.observe_upstream();
+        // retry on configured upstream statuses, cache hits never went upstream
+        let status = upstream_response.status.as_u16();
+        if ctx.tries > 0
+            && ctx
+                .service
+                .and_then(|s| s.retry.as_ref())
+                .is_some_and(|r| r.statuses.contains(&status))
+        {
+            let mut e = pingora::Error::explain(
+                ErrorType::HTTPStatus(status),
+                "[response_filter] retryable upstream status",
+            );
+            if retry::should_retry(ctx, session, &e, retry::Failure::Proxy) {
+                e.set_retry(true);
+                return Err(e);
+            }
+        }
         // add headers
-        match upstream_response.append_header("x-server", "Easy Proxy") {
-            Ok(_) => {}
-            Err(e) => {
+        response_modifiers::route_headers(session, ctx, upstream_response)?;
+        if let Some(c) = ctx.route.and_then(|r| r.compression.as_ref()) {
+            compression::response_filter(session, c, upstream_response);
+        }
+        if ctx.cache_key.is_some() {
+            let _ = upstream_response
+                .insert_header("x-cache", config::cache::status(session.cache.phase()));
+        }
+        if let Some(cookie) = ctx.sticky_cookie.take() {
+            if let Err(e) = upstream_response.append_header("set-cookie", cookie) {
                 return Err(pingora::Error::because(
                     ErrorType::InternalError,
                     "[response_filter]",
@@ -506,24 +964,135 @@ impl ProxyHttp for EasyProxy {
         Ok(())
     }
 
-    // async fn logging(
-    //     &self,
-    //     session: &mut Session,
-    //     _e: Option<&pingora::Error>,
-    //     ctx: &mut Self::CTX,
-    // ) {
-    //     // println!("latency: {:?}", ctx.latency.elapsed().as_micros());
-    //     let response_code = session
-    //         .response_written()
-    //         .map_or(0, |resp| resp.status.as_u16());
-    //     let latency = ctx.latency.elapsed().as_secs_f64();
-    //     metrics::REQUEST_LATENCY.observe(latency);
-    //     if (200..300).contains(&response_code) {
-    //         metrics::SUCCESS_COUNTER.inc();
-    //     } else if (400..500).contains(&response_code) {
-    //         metrics::CLIENT_ERROR_COUNTER.inc();
-    //     } else if (500..600).contains(&response_code) {
-    //         metrics::SERVER_ERROR_COUNTER.inc();
-    //     }
-    // }
+    fn request_cache_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<()> {
+        let cache = ctx.route.and_then(|r| r.cache.as_ref());
+        if let (Some(cache), Some(_)) = (cache, &ctx.cache_key) {
+            let lock = cache
+                .coalesce
+                .unwrap_or(true)
+                .then(|| &*config::cache::LOCK);
+            session.cache.enable(
+                &*config::cache::STORAGE,
+                Some(&*config::cache::EVICTION),
+                None,
+                lock,
+            );
+        }
+        Ok(())
+    }
+
+    fn should_serve_stale(
+        &self,
+        session: &mut Session,
+        _ctx: &mut Self::CTX,
+        error: Option<&pingora::Error>,
+    ) -> bool {
+        session
+            .cache
+            .maybe_cache_meta()
+            .is_some_and(|meta| config::cache::serve_stale(meta, error, SystemTime::now()))
+    }
+
+    fn cache_key_callback(
+        &self,
+        session: &Session,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<CacheKey> {
+        Ok(match &ctx.cache_key {
+            Some(key) => config::cache::key(key),
+            None => CacheKey::default(session.req_header()),
+        })
+    }
+
+    fn response_cache_filter(
+        &self,
+        session: &Session,
+        resp: &ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<RespCacheable> {
+        let Some(cache) = ctx.route.and_then(|r| r.cache.as_ref()) else {
+            return Ok(RespCacheable::Uncacheable(NoCacheReason::NeverEnabled));
+        };
+        let authorization = session.req_header().headers.contains_key("authorization");
+        Ok(config::cache::response_cacheable(
+            cache,
+            resp,
+            authorization,
+        ))
+    }
+
+    fn cache_vary_filter(
+        &self,
+        meta: &CacheMeta,
+        _ctx: &mut Self::CTX,
+        req: &RequestHeader,
+    ) -> Option<HashBinary> {
+        config::cache::variance(meta, req)
+    }
+
+    async fn logging(
+        &self,
+        session: &mut Session,
+        e: Option<&pingora::Error>,
+        ctx: &mut Self::CTX,
+    ) {
+        // a failed upstream counts as a slow one for latency aware algorithms
+        if e.is_some() {
+            ctx.observe_upstream();
+        }
+        ctx.finish_upstream();
+        // requests answered by the proxy itself release the permit without an outcome
+        if let Some(permit) = ctx.circuit_breaker.take().filter(|_| ctx.tries > 0) {
+            let status = session
+                .response_written()
+                .map_or(0, |resp| resp.status.as_u16());
+            let upstream_error = matches!(e, Some(e) if e.esource() == &ErrorSource::Upstream);
+            let success = !upstream_error && !permit.breaker().statuses.contains(&status);
+            permit.record(success);
+        }
+        // let response_code = session
+        //     .response_written()
+        //     .map_or(0, |resp| resp.status.as_u16());
+        // let latency = ctx.latency.elapsed().as_secs_f64();
+        // metrics::REQUEST_LATENCY.observe(latency);
+        // if (200..300).contains(&response_code) {
+        //     metrics::SUCCESS_COUNTER.inc();
+        // } else if (400..500).contains(&response_code) {
+        //     metrics::CLIENT_ERROR_COUNTER.inc();
+        // } else if (500..600).contains(&response_code) {
+        //     metrics::SERVER_ERROR_COUNTER.inc();
+        // }
+    }
+}
+
+// trusted sources of PROXY protocol headers when the listener accepts them
+fn proxy_protocol_trusted(name: &str) -> Result<Option<Vec<Cidr>>, Errors> {
+    let Some(pp) = &config::runtime::config().proxy.proxy_protocol else {
+        return Ok(None);
+    };
+    if pp
+        .listeners
+        .as_ref()
+        .is_some_and(|l| !l.iter().any(|l| l == name))
+    {
+        return Ok(None);
+    }
+    let trusted = pp
+        .trusted
+        .iter()
+        .map(|c| c.parse())
+        .collect::<Result<Vec<_>, Errors>>()?;
+    tracing::info!("PROXY protocol enabled on the {} listener", name);
+    Ok(Some(trusted))
+}
+
+fn is_tls(session: &Session) -> bool {
+    match session.digest() {
+        Some(d) => d.ssl_digest.is_some(),
+        None => false,
+    }
 }
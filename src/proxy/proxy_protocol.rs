@@ -0,0 +1,415 @@
+use crate::config::access::Cidr;
+use crate::errors::Errors;
+use async_trait::async_trait;
+use pingora::{
+    apps::ServerApp,
+    connectors::L4Connect,
+    listeners::TlsAcceptCallbacks,
+    protocols::{
+        l4::{socket::SocketAddr as PeerAddr, stream::Stream as L4Stream},
+        tls::server::handshake_with_callback,
+        GetSocketDigest, SocketDigest,
+    },
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+    tls::ssl::{select_next_proto, AlpnError, SslAcceptor, SslMethod, SslRef},
+    ErrorType,
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    os::fd::{AsRawFd, FromRawFd},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+static V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// longest v1 header including the CRLF
+static V1_MAX_LEN: usize = 107;
+// trusted sources that don't send the header in time are disconnected
+static HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+// ALPN wire format, h2 first
+static ALPN_H2_H1: &[u8] = b"\x02h2\x08http/1.1";
+
+#[derive(Debug, PartialEq)]
+pub enum Header {
+    // more bytes are needed
+    Incomplete,
+    // header length and the source address, `None` for LOCAL / UNKNOWN
+    Complete(usize, Option<SocketAddr>),
+}
+
+// parse a PROXY protocol v1 or v2 header at the start of `buf`
+pub fn parse(buf: &[u8]) -> Result<Header, Errors> {
+    let invalid = |msg: &str| Errors::ProxyError(format!("Invalid PROXY protocol header: {}", msg));
+    if buf.len() < V2_SIGNATURE.len() {
+        let prefix = &buf[..buf.len().min(6)];
+        if V2_SIGNATURE.starts_with(buf) || b"PROXY ".starts_with(prefix) {
+            return Ok(Header::Incomplete);
+        }
+        return Err(invalid("missing signature"));
+    }
+    if buf.starts_with(V2_SIGNATURE) {
+        if buf.len() < 16 {
+            return Ok(Header::Incomplete);
+        }
+        let len = 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+        if buf.len() < len {
+            return Ok(Header::Incomplete);
+        }
+        if buf[12] >> 4 != 2 {
+            return Err(invalid("unsupported version"));
+        }
+        // LOCAL: health checks of the load balancer itself
+        if buf[12] & 0x0f == 0 {
+            return Ok(Header::Complete(len, None));
+        }
+        let addr = &buf[16..len];
+        let source = match buf[13] >> 4 {
+            // AF_INET
+            1 if addr.len() >= 12 => {
+                let ip: [u8; 4] = addr[0..4].try_into().unwrap_or_default();
+                let port = u16::from_be_bytes([addr[8], addr[9]]);
+                Some(SocketAddr::new(IpAddr::from(ip), port))
+            }
+            // AF_INET6
+            2 if addr.len() >= 36 => {
+                let ip: [u8; 16] = addr[0..16].try_into().unwrap_or_default();
+                let port = u16::from_be_bytes([addr[32], addr[33]]);
+                Some(SocketAddr::new(IpAddr::from(ip), port))
+            }
+            0 => None,
+            _ => return Err(invalid("unsupported address family")),
+        };
+        return Ok(Header::Complete(len, source));
+    }
+    if !buf.starts_with(b"PROXY ") {
+        return Err(invalid("missing signature"));
+    }
+    let Some(end) = buf.windows(2).position(|w| w == b"\r\n") else {
+        if buf.len() >= V1_MAX_LEN {
+            return Err(invalid("header too long"));
+        }
+        return Ok(Header::Incomplete);
+    };
+    let line = std::str::from_utf8(&buf[..end]).map_err(|_| invalid("not ascii"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    let source = match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", "TCP4" | "TCP6", src, _dst, src_port, _dst_port] => {
+            let ip = src
+                .parse::<IpAddr>()
+                .map_err(|_| invalid("source address"))?;
+            let port = src_port
+                .parse::<u16>()
+                .map_err(|_| invalid("source port"))?;
+            Some(SocketAddr::new(ip, port))
+        }
+        _ => return Err(invalid(line)),
+    };
+    Ok(Header::Complete(end + 2, source))
+}
+
+// PROXY protocol v2 header for a proxied TCP connection
+pub fn encode_v2(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut buf = V2_SIGNATURE.to_vec();
+    // version 2, PROXY
+    buf.push(0x21);
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            // AF_INET, STREAM
+            buf.push(0x11);
+            buf.extend_from_slice(&12u16.to_be_bytes());
+            buf.extend_from_slice(&src.octets());
+            buf.extend_from_slice(&dst.octets());
+        }
+        (src, dst) => {
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            // AF_INET6, STREAM
+            buf.push(0x21);
+            buf.extend_from_slice(&36u16.to_be_bytes());
+            buf.extend_from_slice(&v6(src).octets());
+            buf.extend_from_slice(&v6(dst).octets());
+        }
+    }
+    buf.extend_from_slice(&source.port().to_be_bytes());
+    buf.extend_from_slice(&destination.port().to_be_bytes());
+    buf
+}
+
+// Pingora service serving the proxy on `addr`, where trusted sources start their connections
+// with a PROXY protocol header. The header is read before the TLS handshake and its source
+// replaces the peer address of the connection. Other sources connect as usual.
+pub struct Listener<A> {
+    name: String,
+    addr: String,
+    tls: Option<Arc<Tls>>,
+    trusted: Arc<Vec<Cidr>>,
+    app: Option<A>,
+}
+
+struct Tls {
+    acceptor: SslAcceptor,
+    callbacks: TlsAcceptCallbacks,
+}
+
+impl<A> Listener<A> {
+    pub fn new(name: &str, addr: &str, trusted: Vec<Cidr>, app: A) -> Self {
+        Self {
+            name: name.to_string(),
+            addr: addr.to_string(),
+            tls: None,
+            trusted: Arc::new(trusted),
+            app: Some(app),
+        }
+    }
+
+    // terminate TLS with the certificates of `callbacks`, h2 is preferred over http/1.1
+    pub fn tls(mut self, callbacks: TlsAcceptCallbacks) -> Result<Self, Errors> {
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())
+            .map_err(|e| Errors::ProxyError(format!("Unable to create TLS acceptor: {}", e)))?;
+        builder.set_alpn_select_callback(prefer_h2);
+        self.tls = Some(Arc::new(Tls {
+            acceptor: builder.build(),
+            callbacks,
+        }));
+        Ok(self)
+    }
+
+    // takes over the listener of a graceful upgrade, or binds `addr` and registers it in the
+    // fd table so that the next upgrade takes it over
+    async fn listen(&self, fds: Option<ListenFds>) -> Result<TcpListener, Errors> {
+        let error = |e: std::io::Error| {
+            Errors::ProxyError(format!("Unable to listen on {}: {}", self.addr, e))
+        };
+        let mut table = match &fds {
+            Some(fds) => Some(fds.lock().await),
+            None => None,
+        };
+        let fd = table.as_ref().and_then(|t| t.get(&self.addr)).copied();
+        let listener = match fd {
+            // the fd was sent by the previous process and is only used here
+            Some(fd) => unsafe { std::net::TcpListener::from_raw_fd(fd) },
+            None => {
+                let listener = std::net::TcpListener::bind(&self.addr).map_err(error)?;
+                if let Some(table) = table.as_mut() {
+                    table.add(self.addr.clone(), listener.as_raw_fd());
+                }
+                listener
+            }
+        };
+        listener.set_nonblocking(true).map_err(error)?;
+        TcpListener::from_std(listener).map_err(error)
+    }
+}
+
+fn prefer_h2<'a>(_: &mut SslRef, alpn_in: &'a [u8]) -> Result<&'a [u8], AlpnError> {
+    select_next_proto(ALPN_H2_H1, alpn_in).ok_or(AlpnError::NOACK)
+}
+
+#[async_trait]
+impl<A: ServerApp + Send + Sync + 'static> Service for Listener<A> {
+    async fn start_service(&mut self, fds: Option<ListenFds>, mut shutdown: ShutdownWatch) {
+        let Some(app) = self.app.take() else {
+            return;
+        };
+        let app = Arc::new(app);
+        let listener = match self.listen(fds).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                tracing::error!("{}", e);
+                return;
+            }
+        };
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    break;
+                }
+                accepted = listener.accept() => {
+                    match accepted {
+                        Ok((stream, peer)) => {
+                            let app = app.clone();
+                            let tls = self.tls.clone();
+                            let trusted = self.trusted.clone();
+                            let shutdown = shutdown.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) = serve(stream, peer, &trusted, tls, app, shutdown).await {
+                                    tracing::debug!("[proxy_protocol] {}: {}", peer, e);
+                                }
+                            });
+                        }
+                        Err(e) => {
+                            tracing::error!("Error accepting connection: {:?}", e);
+                        }
+                    }
+                }
+            }
+        }
+        app.cleanup().await;
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn threads(&self) -> Option<usize> {
+        None
+    }
+}
+
+// read exactly the PROXY protocol header, the stream continues with the first byte after it.
+// `None` for LOCAL / UNKNOWN headers
+async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>, Errors> {
+    let error = |e: std::io::Error| Errors::ProxyError(e.to_string());
+    // no header is shorter than "PROXY UNKNOWN\r\n"
+    let mut buf = vec![0u8; 15];
+    stream.read_exact(&mut buf).await.map_err(error)?;
+    loop {
+        if let Header::Complete(_, addr) = parse(&buf)? {
+            return Ok(addr);
+        }
+        // v2 headers announce their length, v1 headers end with CRLF
+        let more = if buf.starts_with(V2_SIGNATURE) && buf.len() >= 16 {
+            16 + u16::from_be_bytes([buf[14], buf[15]]) as usize - buf.len()
+        } else {
+            1
+        };
+        let len = buf.len();
+        buf.resize(len + more, 0);
+        stream.read_exact(&mut buf[len..]).await.map_err(error)?;
+    }
+}
+
+async fn serve<A: ServerApp + Send + Sync + 'static>(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    trusted: &[Cidr],
+    tls: Option<Arc<Tls>>,
+    app: Arc<A>,
+    shutdown: ShutdownWatch,
+) -> Result<(), Errors> {
+    let local = stream
+        .local_addr()
+        .map_err(|e| Errors::ProxyError(e.to_string()))?;
+    let mut source = peer;
+    if trusted.iter().any(|c| c.contains(&peer.ip())) {
+        let header = tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream))
+            .await
+            .map_err(|_| Errors::ProxyError("PROXY protocol header timed out".to_string()))?;
+        if let Some(addr) = header? {
+            source = addr;
+        }
+    }
+    let mut stream: L4Stream = stream.into();
+    // the session reads the client address from the socket digest
+    let digest = SocketDigest::from_raw_fd(stream.as_raw_fd());
+    let _ = digest.peer_addr.set(Some(PeerAddr::Inet(source)));
+    let _ = digest.local_addr.set(Some(PeerAddr::Inet(local)));
+    stream.set_socket_digest(digest);
+    let stream: pingora::protocols::Stream = match tls {
+        Some(tls) => Box::new(
+            handshake_with_callback(&tls.acceptor, stream, &tls.callbacks)
+                .await
+                .map_err(|e| Errors::ProxyError(e.to_string()))?,
+        ),
+        None => Box::new(stream),
+    };
+    let mut reused = app.process_new(stream, &shutdown).await;
+    while let Some(stream) = reused {
+        reused = app.process_new(stream, &shutdown).await;
+    }
+    Ok(())
+}
+
+// Connects to upstreams with a PROXY protocol v2 header for the client of the request
+#[derive(Debug)]
+pub struct Connect {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+#[async_trait]
+impl L4Connect for Connect {
+    async fn connect(&self, addr: &PeerAddr) -> pingora::Result<L4Stream> {
+        let Some(addr) = addr.as_inet() else {
+            return Err(pingora::Error::explain(
+                ErrorType::ConnectError,
+                "[proxy_protocol] only tcp upstreams are supported",
+            ));
+        };
+        let mut stream = TcpStream::connect(addr)
+            .await
+            .map_err(|e| pingora::Error::because(ErrorType::ConnectError, "[proxy_protocol]", e))?;
+        stream
+            .write_all(&encode_v2(self.source, self.destination))
+            .await
+            .map_err(|e| pingora::Error::because(ErrorType::WriteError, "[proxy_protocol]", e))?;
+        Ok(stream.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    #[test]
+    fn test_parse_v1() {
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(
+            parse(header).unwrap(),
+            Header::Complete(45, Some("192.0.2.1:56324".parse().unwrap()))
+        );
+        assert_eq!(parse(b"PROXY TCP4 192.0").unwrap(), Header::Incomplete);
+        assert_eq!(parse(b"PRO").unwrap(), Header::Incomplete);
+        assert_eq!(
+            parse(b"PROXY UNKNOWN\r\n").unwrap(),
+            Header::Complete(15, None)
+        );
+        assert!(parse(b"GET / HTTP/1.1\r\n").is_err());
+    }
+
+    #[test]
+    fn test_v2_roundtrip() {
+        let source: SocketAddr = "192.0.2.1:56324".parse().unwrap();
+        let header = encode_v2(source, "198.51.100.1:443".parse().unwrap());
+        assert_eq!(header.len(), 28);
+        assert_eq!(parse(&header).unwrap(), Header::Complete(28, Some(source)));
+        assert_eq!(parse(&header[..20]).unwrap(), Header::Incomplete);
+
+        let source = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 8080);
+        let header = encode_v2(source, "198.51.100.1:443".parse().unwrap());
+        assert_eq!(parse(&header).unwrap(), Header::Complete(52, Some(source)));
+    }
+
+    #[tokio::test]
+    async fn test_read_header() {
+        // nothing after the header is consumed
+        let mut stream: &[u8] =
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(
+            read_header(&mut stream).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+
+        let source: SocketAddr = "192.0.2.1:56324".parse().unwrap();
+        let mut data = encode_v2(source, "198.51.100.1:443".parse().unwrap());
+        data.extend_from_slice(b"\x16\x03\x01");
+        let mut stream = data.as_slice();
+        assert_eq!(read_header(&mut stream).await.unwrap(), Some(source));
+        assert_eq!(stream, b"\x16\x03\x01");
+
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut stream).await.unwrap(), None);
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\nHost: a\r\n";
+        assert!(read_header(&mut stream).await.is_err());
+    }
+}
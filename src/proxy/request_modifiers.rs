@@ -1,7 +1,105 @@
-use super::context::Context;
-use crate::config::proxy::Header;
+use super::{context::Context, variables};
+use crate::config::{self, proxy::ForwardedHeaders, proxy::Header};
 use crate::errors::Errors;
+use http::Version;
 use pingora::proxy::Session;
+use std::net::IpAddr;
+
+// `X-Forwarded-*`, `Forwarded` and `Via`
+// - append: the peer is appended to the chain, the other headers are kept when set by a trusted proxy
+// - replace: the chain is replaced by the client IP and the other headers are overwritten
+pub fn forwarded_headers(
+    session: &mut Session,
+    forwarded_headers: &ForwardedHeaders,
+    peer_ip: IpAddr,
+    client_ip: IpAddr,
+    host: &str,
+    is_tls: bool,
+) {
+    let replace = match forwarded_headers.mode.as_deref() {
+        Some("off") => return,
+        Some("replace") => true,
+        _ => false,
+    };
+    let trusted = config::runtime::trusted_proxies()
+        .iter()
+        .any(|c| c.contains(&peer_ip));
+    let keep = !replace && trusted;
+    let proto = if is_tls { "https" } else { "http" };
+    let port = session
+        .server_addr()
+        .and_then(|addr| addr.as_inet())
+        .map(|addr| addr.port().to_string())
+        .unwrap_or_default();
+    let host = session
+        .get_header("host")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or(host)
+        .to_string();
+    let version = match session.req_header().version {
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        _ => "1.1",
+    };
+
+    let header = |session: &Session, name: &str| -> Option<String> {
+        let values: Vec<&str> = session
+            .req_header()
+            .headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect();
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.join(", "))
+        }
+    };
+    let chain = |session: &Session, name: &str, value: String| -> String {
+        match header(session, name) {
+            Some(existing) if !replace => format!("{}, {}", existing, value),
+            _ => value,
+        }
+    };
+
+    let xff = if replace {
+        client_ip.to_string()
+    } else {
+        chain(session, "x-forwarded-for", peer_ip.to_string())
+    };
+    let _ = session
+        .req_header_mut()
+        .insert_header("x-forwarded-for", xff);
+    for (name, value) in [
+        ("x-forwarded-proto", proto.to_string()),
+        ("x-forwarded-host", host.clone()),
+        ("x-forwarded-port", port),
+    ] {
+        if (keep && header(session, name).is_some()) || value.is_empty() {
+            continue;
+        }
+        let _ = session.req_header_mut().insert_header(name, value);
+    }
+    if forwarded_headers.forwarded.unwrap_or(false) {
+        let for_ip = if replace { client_ip } else { peer_ip };
+        let node = match for_ip {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+        };
+        let element = format!("for={};host=\"{}\";proto={}", node, host, proto);
+        let value = chain(session, "forwarded", element);
+        let _ = session.req_header_mut().insert_header("forwarded", value);
+    }
+    if forwarded_headers.via.unwrap_or(false) {
+        // appended in both modes
+        let value = match header(session, "via") {
+            Some(existing) => format!("{}, {} easy-proxy", existing, version),
+            None => format!("{} easy-proxy", version),
+        };
+        let _ = session.req_header_mut().insert_header("via", value);
+    }
+}
 
 pub fn headers(
     session: &mut Session,
@@ -14,27 +112,12 @@ pub fn headers(
     }
 
     for header in add_headers {
-        let mut value = header.value.clone();
-
-        // Replace variables in the header value.
-        for (k, v) in &ctx.variables {
-            value = value.replace(&format!("${}", k), v);
-        }
-
-        if value.starts_with("$HK_") {
-            let key = value.replace("$HK_", "").to_ascii_lowercase();
-            let value = session.get_header(&key);
-            if let Some(value) = value.cloned() {
-                let _ = session
-                    .req_header_mut()
-                    .append_header(header.name.clone(), value);
-            }
+        let Some(value) = variables::expand_header(&header.value, session.req_header(), ctx) else {
             continue;
-        }
-
+        };
         let _ = session
             .req_header_mut()
-            .append_header(header.name.clone(), &value);
+            .append_header(header.name.clone(), value);
     }
 }
 
@@ -1,3 +1,4 @@
+use super::{context::Context, response_modifiers};
 use crate::errors::Errors;
 use bytes::Bytes;
 use pingora::{http::ResponseHeader, protocols::http::HttpTask, proxy::Session, ErrorType};
@@ -34,10 +35,14 @@ impl<'a> Response<'a> {
         path: String,
         port: Option<String>,
     ) -> &mut Self {
-        self.status(301);
         let port_str = port.unwrap_or_default();
         let location = format!("https://{}{}{}", host, port_str, path);
-        self.header("Location", &location);
+        self.redirect(&location, 301)
+    }
+
+    pub fn redirect(&mut self, location: &str, status: u16) -> &mut Self {
+        self.status(status);
+        self.header("Location", location);
         self.header("Content-Length", "0");
         self
     }
@@ -79,6 +84,12 @@ impl<'a> Response<'a> {
         Ok(self)
     }
 
+    // headers of the matched route, for responses sent in place of an upstream response
+    pub fn route_headers(&mut self, ctx: &Context) -> pingora::Result<&mut Self> {
+        response_modifiers::route_headers(self.session, ctx, &mut self.headers)?;
+        Ok(self)
+    }
+
     pub async fn send(&mut self) -> pingora::Result<bool> {
         let tasks = vec![
             HttpTask::Header(Box::new(self.headers.clone()), false),
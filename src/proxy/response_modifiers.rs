@@ -0,0 +1,305 @@
+use super::{constant::CORS_RESPONSE_HEADERS, context::Context, variables};
+use crate::config::{
+    cors::CorsPolicy,
+    proxy::{Header, SecurityHeaders},
+    runtime,
+    store::Route,
+};
+use crate::errors::Errors;
+use pingora::{http::ResponseHeader, proxy::Session, ErrorType};
+
+static DEFAULT_SERVER: &str = "Easy Proxy";
+static DEFAULT_HSTS: &str = "max-age=31536000; includeSubDomains";
+static DEFAULT_FRAME_OPTIONS: &str = "DENY";
+static DEFAULT_REFERRER_POLICY: &str = "strict-origin-when-cross-origin";
+
+fn header_error(e: impl std::fmt::Display) -> Box<pingora::Error> {
+    pingora::Error::because(
+        ErrorType::InternalError,
+        "[response_modifiers]",
+        Errors::ConfigError(format!("Unable to add header: {}", e)),
+    )
+}
+
+// server header, response headers, security headers and CORS of the matched route,
+// for upstream responses and the ones the proxy generates itself
+pub fn route_headers(
+    session: &Session,
+    ctx: &Context,
+    response: &mut ResponseHeader,
+) -> pingora::Result<()> {
+    let server = runtime::config()
+        .server_header
+        .as_deref()
+        .unwrap_or(DEFAULT_SERVER);
+    let cors = ctx
+        .cors
+        .as_ref()
+        .map(|(cors, origin)| (*cors, origin.as_str()));
+    apply(
+        response,
+        ctx.route,
+        cors,
+        server,
+        super::is_tls(session),
+        |value| variables::expand(value, session, ctx),
+    )
+}
+
+fn apply<F>(
+    response: &mut ResponseHeader,
+    route: Option<&Route>,
+    cors: Option<(&CorsPolicy, &str)>,
+    server: &str,
+    is_tls: bool,
+    expand: F,
+) -> pingora::Result<()>
+where
+    F: Fn(&str) -> String,
+{
+    if !server.is_empty() {
+        response
+            .append_header("x-server", server)
+            .map_err(header_error)?;
+    }
+    if let Some(route) = route {
+        headers(
+            response,
+            route.response_add_headers.as_deref().unwrap_or_default(),
+            route.response_set_headers.as_deref().unwrap_or_default(),
+            route.response_remove_headers.as_deref().unwrap_or_default(),
+            &expand,
+        );
+        if let Some(preset) = &route.security_headers {
+            security_headers(response, preset, is_tls);
+        }
+    }
+    // the route policy replaces the one of the upstream, also for origins it doesn't allow
+    if cors.is_some() || route.is_some_and(|r| r.cors.is_some()) {
+        for name in CORS_RESPONSE_HEADERS {
+            let _ = response.remove_header(name);
+        }
+    }
+    if let Some((cors, origin)) = cors {
+        for (name, value) in cors.response_headers(origin) {
+            response.append_header(name, value).map_err(header_error)?;
+        }
+    }
+    Ok(())
+}
+
+// remove, then set (replace), then add (append) headers of the response
+fn headers<F>(
+    response: &mut ResponseHeader,
+    add_headers: &[Header],
+    set_headers: &[Header],
+    remove_headers: &[String],
+    expand: &F,
+) where
+    F: Fn(&str) -> String,
+{
+    for name in remove_headers {
+        let _ = response.remove_header(name.as_str());
+    }
+    for header in set_headers {
+        let _ = response.insert_header(header.name.clone(), expand(&header.value));
+    }
+    for header in add_headers {
+        let _ = response.append_header(header.name.clone(), expand(&header.value));
+    }
+}
+
+// preset of security headers, set unless the upstream sent them, an empty value disables one
+pub fn security_headers(response: &mut ResponseHeader, preset: &SecurityHeaders, is_tls: bool) {
+    let value =
+        |v: &Option<String>, default: &str| v.clone().unwrap_or_else(|| default.to_string());
+    let mut headers = vec![
+        (
+            "x-frame-options",
+            value(&preset.frame_options, DEFAULT_FRAME_OPTIONS),
+        ),
+        (
+            "referrer-policy",
+            value(&preset.referrer_policy, DEFAULT_REFERRER_POLICY),
+        ),
+        (
+            "content-security-policy",
+            value(&preset.content_security_policy, ""),
+        ),
+    ];
+    if preset.content_type_options.unwrap_or(true) {
+        headers.push(("x-content-type-options", "nosniff".to_string()));
+    }
+    // browsers ignore HSTS over plain http
+    if is_tls {
+        headers.push((
+            "strict-transport-security",
+            value(&preset.hsts, DEFAULT_HSTS),
+        ));
+    }
+    for (name, value) in headers {
+        if value.is_empty() || response.headers.contains_key(name) {
+            continue;
+        }
+        let _ = response.insert_header(name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::proxy::{Cors, Path, ServiceReference};
+    use std::sync::Arc;
+
+    fn route() -> Route {
+        let header = |name: &str, value: &str| Header {
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+        Route {
+            action: None,
+            path: Path {
+                path_type: "Prefix".to_string(),
+                path: "/".to_string(),
+                service: ServiceReference::default(),
+                return_response: None,
+                redirect: None,
+            },
+            service: ServiceReference::default(),
+            remove_headers: None,
+            add_headers: None,
+            tls: None,
+            rate_limit: None,
+            access: None,
+            forwarded_headers: None,
+            basic_auth: None,
+            jwt: None,
+            forward_auth: None,
+            cors: None,
+            response_add_headers: Some(vec![header("x-client-ip", "$CLIENT_IP")]),
+            response_set_headers: Some(vec![header("cache-control", "no-store")]),
+            response_remove_headers: None,
+            security_headers: Some(SecurityHeaders {
+                content_security_policy: Some("default-src 'self'".to_string()),
+                ..Default::default()
+            }),
+            compression: None,
+            cache: None,
+        }
+    }
+
+    #[test]
+    fn test_generated_response() {
+        let cors = CorsPolicy::new(
+            "test",
+            &Cors {
+                allow_origins: Some(vec!["https://app.example.com".to_string()]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        // e.g. a file of a static service
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response
+            .insert_header("content-type", "text/html; charset=utf-8")
+            .unwrap();
+        let expand = |value: &str| value.replace("$CLIENT_IP", "10.0.0.1");
+        apply(
+            &mut response,
+            Some(&route()),
+            Some((&cors, "https://app.example.com")),
+            "Easy Proxy",
+            true,
+            expand,
+        )
+        .unwrap();
+        let header = |name: &str| {
+            response
+                .headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        assert_eq!(header("x-server").as_deref(), Some("Easy Proxy"));
+        assert_eq!(header("x-client-ip").as_deref(), Some("10.0.0.1"));
+        assert_eq!(header("cache-control").as_deref(), Some("no-store"));
+        assert_eq!(
+            header("content-security-policy").as_deref(),
+            Some("default-src 'self'")
+        );
+        assert_eq!(
+            header("strict-transport-security").as_deref(),
+            Some(DEFAULT_HSTS)
+        );
+        assert_eq!(
+            header("access-control-allow-origin").as_deref(),
+            Some("https://app.example.com")
+        );
+
+        // an empty server header drops it
+        let mut response = ResponseHeader::build(404, None).unwrap();
+        apply(&mut response, None, None, "", false, expand).unwrap();
+        assert!(response.headers.get("x-server").is_none());
+    }
+
+    #[test]
+    fn test_upstream_cors_headers() {
+        let cors = CorsPolicy::new(
+            "test",
+            &Cors {
+                allow_origins: Some(vec!["https://app.example.com".to_string()]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let upstream = || {
+            let mut response = ResponseHeader::build(200, None).unwrap();
+            response
+                .insert_header("access-control-allow-origin", "*")
+                .unwrap();
+            response
+                .insert_header("access-control-allow-credentials", "true")
+                .unwrap();
+            response
+        };
+        let expand = |value: &str| value.to_string();
+
+        // a disallowed origin gets none of the upstream CORS headers
+        let mut response = upstream();
+        apply(
+            &mut response,
+            None,
+            Some((&cors, "https://evil.example.com")),
+            "",
+            false,
+            expand,
+        )
+        .unwrap();
+        assert!(response
+            .headers
+            .get("access-control-allow-origin")
+            .is_none());
+        assert!(response
+            .headers
+            .get("access-control-allow-credentials")
+            .is_none());
+
+        // neither does a request without origin on a route with a policy
+        let mut route = route();
+        route.cors = Some(Arc::new(cors));
+        let mut response = upstream();
+        apply(&mut response, Some(&route), None, "", false, expand).unwrap();
+        assert!(response
+            .headers
+            .get("access-control-allow-origin")
+            .is_none());
+
+        // routes without a policy pass them through
+        let mut response = upstream();
+        apply(&mut response, None, None, "", false, expand).unwrap();
+        assert!(response
+            .headers
+            .get("access-control-allow-origin")
+            .is_some());
+    }
+}
@@ -0,0 +1,35 @@
+use super::context::Context;
+use pingora::{proxy::Session, Error, ErrorType};
+
+pub enum Failure {
+    // the upstream connection could not be established
+    Connect,
+    // the upstream failed while proxying, including retryable statuses
+    Proxy,
+}
+
+// whether the retry policy of the service allows another attempt
+pub fn should_retry(ctx: &Context, session: &Session, e: &Error, failure: Failure) -> bool {
+    let Some(policy) = ctx.service.and_then(|s| s.retry.as_ref()) else {
+        return false;
+    };
+    if ctx.tries >= policy.attempts {
+        return false;
+    }
+    let method = session.req_header().method.as_str();
+    if !policy.methods.iter().any(|m| m == method) {
+        return false;
+    }
+    match e.etype() {
+        ErrorType::HTTPStatus(status) => policy.statuses.contains(status),
+        ErrorType::ConnectTimedout | ErrorType::ReadTimedout | ErrorType::WriteTimedout
+            if policy.on_timeout =>
+        {
+            true
+        }
+        _ => match failure {
+            Failure::Connect => policy.on_connect_error,
+            Failure::Proxy => policy.on_error,
+        },
+    }
+}
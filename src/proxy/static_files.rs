@@ -0,0 +1,204 @@
+use super::{context::Context, response::Response, response_modifiers};
+use crate::config::static_files::{
+    directory_location, mime_type, parse_range, Range, Resolved, StaticFiles,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use http::Method;
+use pingora::{http::ResponseHeader, proxy::Session, ErrorType};
+use serde_json::json;
+use std::{io::SeekFrom, path::PathBuf, time::UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+static CHUNK_SIZE: u64 = 64 * 1024;
+// precompressed variants, in order of preference
+static ENCODINGS: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+fn internal_error(e: impl std::error::Error + Send + Sync + 'static) -> Box<pingora::Error> {
+    pingora::Error::because(ErrorType::InternalError, "[static_files]", e)
+}
+
+fn header(session: &Session, name: &str) -> Option<String> {
+    session
+        .get_header(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+// serve a file of a `static` service, the response is streamed in chunks
+pub async fn serve(
+    session: &mut Session,
+    ctx: &Context,
+    files: &StaticFiles,
+    path: &str,
+) -> pingora::Result<bool> {
+    let method = session.req_header().method.clone();
+    if method != Method::GET && method != Method::HEAD {
+        return Response::new(session)
+            .await?
+            .status(405)
+            .header("Allow", "GET, HEAD")
+            .body_json(json!({
+                "error": "METHOD_NOT_ALLOWED",
+                "message": "Method not allowed",
+            }))?
+            .route_headers(ctx)?
+            .send()
+            .await;
+    }
+    let (file, meta) = match files.resolve(path).await {
+        Resolved::File(file, meta) => (file, meta),
+        Resolved::Redirect => {
+            let location = directory_location(&session.req_header().uri);
+            return Response::new(session)
+                .await?
+                .status(301)
+                .header("Location", &location)
+                .header("Content-Length", "0")
+                .route_headers(ctx)?
+                .send()
+                .await;
+        }
+        Resolved::NotFound => {
+            return Response::new(session)
+                .await?
+                .status(404)
+                .body_json(json!({
+                    "error": "NOT_FOUND",
+                    "message": "File not found",
+                }))?
+                .route_headers(ctx)?
+                .send()
+                .await;
+        }
+    };
+
+    // a precompressed `.br` / `.gz` next to the file when the client accepts it
+    let content_type = mime_type(&file);
+    let mut encoding = None;
+    let (file, meta) = if files.precompressed {
+        let accepted = header(session, "accept-encoding").unwrap_or_default();
+        let accepts = |name: &str| {
+            accepted
+                .split(',')
+                .filter_map(|e| e.split(';').next())
+                .any(|e| e.trim().eq_ignore_ascii_case(name))
+        };
+        let mut variant = None;
+        for (name, ext) in ENCODINGS.iter().filter(|(name, _)| accepts(name)) {
+            let mut path = file.clone().into_os_string();
+            path.push(format!(".{}", ext));
+            if let Some(found) = files.file(&PathBuf::from(path)).await {
+                variant = Some((*name, found));
+                break;
+            }
+        }
+        match variant {
+            Some((name, found)) => {
+                encoding = Some(name);
+                found
+            }
+            None => (file, meta),
+        }
+    } else {
+        (file, meta)
+    };
+
+    let len = meta.len();
+    let modified = meta.modified().map_err(internal_error)?;
+    let mtime = modified
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let etag = format!("\"{:x}-{:x}\"", mtime, len);
+    let last_modified = DateTime::<Utc>::from(modified)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string();
+
+    // conditional requests, If-None-Match takes precedence
+    let not_modified = match header(session, "if-none-match") {
+        Some(tags) => tags
+            .split(',')
+            .map(|t| t.trim().trim_start_matches("W/"))
+            .any(|t| t == "*" || t == etag),
+        None => header(session, "if-modified-since")
+            .and_then(|v| DateTime::parse_from_rfc2822(&v).ok())
+            .is_some_and(|since| mtime as i64 <= since.timestamp()),
+    };
+    if not_modified {
+        return Response::new(session)
+            .await?
+            .status(304)
+            .header("ETag", &etag)
+            .header("Last-Modified", &last_modified)
+            .route_headers(ctx)?
+            .send()
+            .await;
+    }
+
+    // a range is only served from the representation the client already has
+    let range = match header(session, "if-range") {
+        Some(validator) if validator != etag && validator != last_modified => None,
+        _ => header(session, "range").and_then(|r| parse_range(&r, len)),
+    };
+    let (status, start, end) = match range {
+        Some(Range::Bytes(start, end)) => (206, start, end),
+        Some(Range::Unsatisfiable) => {
+            return Response::new(session)
+                .await?
+                .status(416)
+                .header("Content-Range", &format!("bytes */{}", len))
+                .header("Content-Length", "0")
+                .route_headers(ctx)?
+                .send()
+                .await;
+        }
+        None => (200, 0, len.saturating_sub(1)),
+    };
+    let body_len = if len == 0 { 0 } else { end - start + 1 };
+
+    let mut resp = ResponseHeader::build(status, None)?;
+    resp.insert_header("Content-Type", content_type)?;
+    resp.insert_header("Content-Length", body_len.to_string())?;
+    resp.insert_header("ETag", &etag)?;
+    resp.insert_header("Last-Modified", &last_modified)?;
+    resp.insert_header("Accept-Ranges", "bytes")?;
+    if status == 206 {
+        resp.insert_header("Content-Range", format!("bytes {}-{}/{}", start, end, len))?;
+    }
+    if let Some(encoding) = encoding {
+        resp.insert_header("Content-Encoding", encoding)?;
+    }
+    if files.precompressed {
+        resp.insert_header("Vary", "Accept-Encoding")?;
+    }
+    response_modifiers::route_headers(session, ctx, &mut resp)?;
+    let head = method == Method::HEAD || body_len == 0;
+    session.write_response_header(Box::new(resp), head).await?;
+    if head {
+        return Ok(true);
+    }
+
+    let mut f = tokio::fs::File::open(&file).await.map_err(internal_error)?;
+    f.seek(SeekFrom::Start(start))
+        .await
+        .map_err(internal_error)?;
+    let mut remaining = body_len;
+    while remaining > 0 {
+        let mut buf = vec![0u8; remaining.min(CHUNK_SIZE) as usize];
+        let n = f.read(&mut buf).await.map_err(internal_error)?;
+        if n == 0 {
+            // the file was truncated while it was sent
+            return Err(pingora::Error::explain(
+                ErrorType::InternalError,
+                "[static_files] unexpected end of file",
+            ));
+        }
+        buf.truncate(n);
+        remaining -= n as u64;
+        session
+            .write_response_body(Some(Bytes::from(buf)), remaining == 0)
+            .await?;
+    }
+    Ok(true)
+}
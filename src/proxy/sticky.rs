@@ -0,0 +1,129 @@
+use super::variables::cookie_value;
+use crate::config::{proxy::Sticky, store::HttpService};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use pingora::{lb::Backend, proxy::Session};
+use sha2::Sha256;
+
+pub static DEFAULT_COOKIE: &str = "easy-proxy-sticky";
+// bytes of the hmac kept in the cookie
+const ID_LEN: usize = 16;
+
+fn cookie_name(sticky: &Sticky) -> &str {
+    sticky.cookie.as_deref().unwrap_or(DEFAULT_COOKIE)
+}
+
+fn mac(sticky: &Sticky) -> Hmac<Sha256> {
+    // hmac accepts keys of any length
+    Hmac::<Sha256>::new_from_slice(sticky.secret.as_bytes()).expect("HMAC can take key of any size")
+}
+
+// cookie value: base64 of a keyed hash of the backend address, so the address isn't exposed
+pub fn encode(sticky: &Sticky, backend: &Backend) -> String {
+    let mut mac = mac(sticky);
+    mac.update(backend.addr.to_string().as_bytes());
+    BASE64_URL_SAFE_NO_PAD.encode(&mac.finalize().into_bytes()[..ID_LEN])
+}
+
+// the backend whose keyed hash is the cookie value
+pub fn decode<'a>(
+    sticky: &Sticky,
+    value: &str,
+    mut backends: impl Iterator<Item = &'a Backend>,
+) -> Option<&'a Backend> {
+    let id = BASE64_URL_SAFE_NO_PAD.decode(value).ok()?;
+    if id.len() != ID_LEN {
+        return None;
+    }
+    backends.find(|b| {
+        let mut mac = mac(sticky);
+        mac.update(b.addr.to_string().as_bytes());
+        mac.verify_truncated_left(&id).is_ok()
+    })
+}
+
+// the backend pinned by the sticky cookie, if it still exists and is healthy
+pub fn backend(sticky: &Sticky, session: &Session, service: &HttpService) -> Option<Backend> {
+    let value = session
+        .req_header()
+        .headers
+        .get_all("cookie")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .find_map(|v| cookie_value(v, cookie_name(sticky)))?;
+    let backends = service.backend_type.backends();
+    let all = backends.get_backend();
+    let backend = decode(sticky, value, all.iter())?.clone();
+    if !backends.ready(&backend) {
+        return None;
+    }
+    Some(backend)
+}
+
+pub fn set_cookie(sticky: &Sticky, backend: &Backend, is_tls: bool) -> String {
+    let mut cookie = format!(
+        "{}={}; Path={}; HttpOnly",
+        cookie_name(sticky),
+        encode(sticky, backend),
+        sticky.path.as_deref().unwrap_or("/")
+    );
+    if let Some(ttl) = sticky.ttl {
+        cookie.push_str(&format!("; Max-Age={}", ttl));
+    }
+    let same_site = sticky.same_site.as_deref().unwrap_or("Lax");
+    cookie.push_str(&format!("; SameSite={}", same_site));
+    // browsers reject `SameSite=None` without `Secure`
+    if is_tls || same_site == "None" {
+        cookie.push_str("; Secure");
+    }
+    cookie
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sticky() -> Sticky {
+        Sticky {
+            cookie: None,
+            secret: "secret".to_string(),
+            ttl: Some(60),
+            path: None,
+            same_site: None,
+        }
+    }
+
+    #[test]
+    fn test_sticky_cookie() {
+        let sticky = sticky();
+        let backends = [
+            Backend::new("127.0.0.1:3000").unwrap(),
+            Backend::new("127.0.0.1:3001").unwrap(),
+        ];
+        let value = encode(&sticky, &backends[1]);
+        assert!(!value.contains("127.0.0.1"));
+        let decoded = decode(&sticky, &value, backends.iter());
+        assert_eq!(
+            decoded.map(|b| b.addr.to_string()).as_deref(),
+            Some("127.0.0.1:3001")
+        );
+
+        // the address itself, a truncated value or another secret is rejected
+        let plain = BASE64_URL_SAFE_NO_PAD.encode("127.0.0.1:3001");
+        assert!(decode(&sticky, &plain, backends.iter()).is_none());
+        assert!(decode(&sticky, &value[..8], backends.iter()).is_none());
+        let other = Sticky {
+            secret: "other".to_string(),
+            ..sticky.clone()
+        };
+        assert!(decode(&other, &value, backends.iter()).is_none());
+
+        assert_eq!(
+            set_cookie(&sticky, &backends[1], true),
+            format!(
+                "easy-proxy-sticky={}; Path=/; HttpOnly; Max-Age=60; SameSite=Lax; Secure",
+                value
+            )
+        );
+    }
+}
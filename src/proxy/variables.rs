@@ -0,0 +1,177 @@
+use super::context::Context;
+use pingora::{http::RequestHeader, proxy::Session};
+
+// variables that read from the request, the rest of the name may contain `-`
+static REQUEST_PREFIXES: [&str; 4] = ["HEADER_", "HK_", "COOKIE_", "QUERY_"];
+
+// Expand `$NAME` variables in a template:
+// - context variables, e.g. `$CLIENT_IP`, `$HOST`, `$PATH`, `$AUTH_USER`, `$JWT_<claim>`
+// - `$HEADER_<name>`, `$COOKIE_<name>` and `$QUERY_<name>` from the request
+//   (`$HK_<name>` is the older spelling of `$HEADER_<name>`)
+// Unknown variables expand to an empty string, see `expand_header` for request headers.
+pub fn expand(template: &str, session: &Session, ctx: &Context) -> String {
+    expand_with(template, |name| resolve(name, session.req_header(), ctx))
+}
+
+pub fn resolve(name: &str, req: &RequestHeader, ctx: &Context) -> Option<String> {
+    if let Some(value) = ctx.variables.get(name) {
+        return Some(value.clone());
+    }
+    if let Some(header) = name
+        .strip_prefix("HEADER_")
+        .or_else(|| name.strip_prefix("HK_"))
+    {
+        return req
+            .headers
+            .get(header.to_ascii_lowercase())
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+    }
+    if let Some(cookie) = name.strip_prefix("COOKIE_") {
+        return req
+            .headers
+            .get_all("cookie")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .find_map(|v| cookie_value(v, cookie))
+            .map(|v| v.to_string());
+    }
+    if let Some(param) = name.strip_prefix("QUERY_") {
+        return query_value(req.uri.query()?, param).map(|v| v.to_string());
+    }
+    None
+}
+
+// Expand a request header value of `add_headers`: `None` when a request variable it
+// references is missing, so the header isn't added, and unknown variables are kept as they are
+pub fn expand_header(template: &str, req: &RequestHeader, ctx: &Context) -> Option<String> {
+    try_expand_with(template, |name| match resolve(name, req, ctx) {
+        Some(value) => Some(value),
+        None if REQUEST_PREFIXES.iter().any(|p| name.starts_with(p)) => None,
+        None => Some(format!("${}", name)),
+    })
+}
+
+pub fn expand_with<F>(template: &str, resolve: F) -> String
+where
+    F: Fn(&str) -> Option<String>,
+{
+    try_expand_with(template, |name| Some(resolve(name).unwrap_or_default())).unwrap_or_default()
+}
+
+// `resolve` returns the value of a variable, `None` stops the expansion
+fn try_expand_with<F>(template: &str, resolve: F) -> Option<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let mut len = after
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(after.len());
+        if REQUEST_PREFIXES.iter().any(|p| after[..len].starts_with(p)) {
+            len = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+                .unwrap_or(after.len());
+        }
+        if len == 0 {
+            out.push('$');
+        } else {
+            out.push_str(&resolve(&after[..len])?);
+        }
+        rest = &after[len..];
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+pub fn cookie_value<'a>(cookie_header: &'a str, name: &str) -> Option<&'a str> {
+    cookie_header
+        .split(';')
+        .filter_map(|c| c.trim().split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+pub fn query_value<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .map(|q| q.split_once('=').unwrap_or((q, "")))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_with() {
+        let resolve = |name: &str| match name {
+            "CLIENT_IP" => Some("10.0.0.1".to_string()),
+            "PATH" => Some("/api".to_string()),
+            "HEADER_x-user-id" => Some("42".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            expand_with("$CLIENT_IP-$PATH", resolve),
+            "10.0.0.1-/api".to_string()
+        );
+        assert_eq!(
+            expand_with("user:$HEADER_x-user-id", resolve),
+            "user:42".to_string()
+        );
+        assert_eq!(expand_with("$UNKNOWN|$", resolve), "|$".to_string());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut req = RequestHeader::build("GET", b"/?tenant=acme", None).unwrap();
+        req.insert_header("x-user-id", "42").unwrap();
+        let mut ctx = Context::new();
+        ctx.variables
+            .insert("CLIENT_IP".to_string(), "10.0.0.1".to_string());
+        let resolve = |name: &str| resolve(name, &req, &ctx);
+        assert_eq!(resolve("CLIENT_IP"), Some("10.0.0.1".to_string()));
+        assert_eq!(resolve("HEADER_x-user-id"), Some("42".to_string()));
+        assert_eq!(resolve("HK_X-User-Id"), Some("42".to_string()));
+        assert_eq!(resolve("QUERY_tenant"), Some("acme".to_string()));
+        assert_eq!(resolve("COOKIE_session"), None);
+    }
+
+    #[test]
+    fn test_expand_header() {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("x-user-id", "42").unwrap();
+        let mut ctx = Context::new();
+        ctx.variables
+            .insert("CLIENT_IP".to_string(), "10.0.0.1".to_string());
+        assert_eq!(
+            expand_header("$CLIENT_IP/$HK_x-user-id", &req, &ctx),
+            Some("10.0.0.1/42".to_string())
+        );
+        // a missing request header skips the header instead of sending it empty
+        assert_eq!(expand_header("$HK_x-tenant", &req, &ctx), None);
+        assert_eq!(expand_header("id:$HEADER_x-tenant", &req, &ctx), None);
+        // unknown variables are sent as they are
+        assert_eq!(
+            expand_header("$CUSTOM-$CLIENT_IP", &req, &ctx),
+            Some("$CUSTOM-10.0.0.1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_cookie_and_query() {
+        assert_eq!(
+            cookie_value("a=1; session=abc; b=2", "session"),
+            Some("abc")
+        );
+        assert_eq!(cookie_value("a=1", "session"), None);
+        assert_eq!(query_value("tenant=acme&x", "tenant"), Some("acme"));
+        assert_eq!(query_value("tenant=acme&x", "x"), Some(""));
+        assert_eq!(query_value("tenant=acme", "y"), None);
+    }
+}